@@ -0,0 +1,234 @@
+//! Headless world generation preview.
+//!
+//! Generates a region of the world and writes it to a PNG without opening a window.
+//!
+//! ```text
+//! kuilu-worldgen [--seed N] [--region X0 Y0 X1 Y1] [--out FILE]
+//!                [--no-surface] [--surface-height N] [--no-bedrock]
+//!                [--no-caves] [--no-prefabs] [--tunnel-width N] [--cave-frequency F]
+//!                [--smoothing-steps N] [--target-depth N] [--min-cave-size N]
+//!                [--chunk-borders] [--stats] [--simulate N] [--hashes] [--deltas FILE]
+//! ```
+//!
+//! `--simulate` runs the texel simulation for N ticks before writing the image. `--hashes` prints the state hash after
+//! every tick, so that the output of two runs can be compared to check that the simulation is deterministic.
+//! `--deltas` writes the texel changes of the simulated ticks to a file, encoded with `TerrainDelta2D::encode`.
+
+use std::{env, fmt, fs, fs::File, io::BufWriter, process::ExitCode, str::FromStr};
+
+use kuilu::{
+    game::{camera::WORLD_WIDTH, prefab_rules},
+    terrain2d::*,
+    util::{Rect2I, Vector2I},
+};
+
+struct Options {
+    seed: u32,
+    region: Rect2I,
+    out: String,
+    surface: Option<SurfaceSettings2D>,
+    bedrock: Option<BedrockSettings2D>,
+    caves: Option<CaveSettings2D>,
+    prefabs: bool,
+    chunk_borders: bool,
+    stats: bool,
+    simulate: u64,
+    hashes: bool,
+    deltas: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            seed: 432678,
+            region: Rect2I::new(Vector2I::ZERO, Vector2I::ONE * WORLD_WIDTH - Vector2I::ONE),
+            out: "worldgen.png".to_string(),
+            surface: Some(SurfaceSettings2D::default()),
+            bedrock: Some(BedrockSettings2D::default()),
+            caves: Some(CaveSettings2D::default()),
+            prefabs: true,
+            chunk_borders: false,
+            stats: false,
+            simulate: 0,
+            hashes: false,
+            deltas: None,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut terrain = Terrain2D::new(None, None, None, None);
+    let mut terrain_gen = TerrainGen2D::new(options.seed);
+    terrain_gen.surface = options.surface.clone();
+    terrain_gen.bedrock = options.bedrock.clone();
+    if let Some(settings) = &options.caves {
+        terrain_gen.generate_caves(settings.clone(), options.region);
+    }
+    terrain_gen.gen_region(&mut terrain, &options.region);
+    if options.prefabs {
+        terrain_gen.place_prefabs(&mut terrain, options.region, &prefab_rules());
+    }
+
+    let mut random = SimulationRandom2D::new(options.seed as u64);
+    let mut deltas = vec![];
+    let mut delta_changes = 0;
+    if options.deltas.is_some() {
+        terrain.enable_delta_stream();
+    }
+    for tick in 1..=options.simulate {
+        terrain.step(tick, &mut random.random);
+        if options.hashes {
+            println!("tick {tick}: {:016x}", terrain.state_hash());
+        }
+        if let Some(delta) = terrain.take_delta(tick) {
+            delta_changes += delta.changes.len();
+            delta.encode(&mut deltas);
+        }
+    }
+    if let Some(path) = &options.deltas {
+        if let Err(err) = fs::write(path, &deltas) {
+            eprintln!("could not write {path}: {err}");
+            return ExitCode::FAILURE;
+        }
+        println!(
+            "wrote {path}: {delta_changes} changes in {} bytes",
+            deltas.len()
+        );
+    }
+
+    let mut data = terrain.rect_to_rgba8(&options.region);
+    if options.chunk_borders {
+        draw_chunk_borders(&options.region, &mut data);
+    }
+    let file = match File::create(&options.out) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("could not create {}: {err}", options.out);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(err) = encode_png_rgba8(
+        BufWriter::new(file),
+        options.region.width() as u32,
+        options.region.height() as u32,
+        &data,
+    ) {
+        eprintln!("could not write {}: {err}", options.out);
+        return ExitCode::FAILURE;
+    }
+    println!("wrote {} {}", options.out, options.region);
+
+    if options.stats {
+        print_stats(&terrain, &options.region);
+    }
+    ExitCode::SUCCESS
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => options.seed = parse_next(&mut args, &arg)?,
+            "--region" => {
+                let x0 = parse_next(&mut args, &arg)?;
+                let y0 = parse_next(&mut args, &arg)?;
+                let x1 = parse_next(&mut args, &arg)?;
+                let y1 = parse_next(&mut args, &arg)?;
+                options.region = Rect2I::from_corners(Vector2I::new(x0, y0), Vector2I::new(x1, y1));
+            }
+            "--out" => options.out = parse_next(&mut args, &arg)?,
+            "--no-surface" => options.surface = None,
+            "--surface-height" => {
+                options
+                    .surface
+                    .as_mut()
+                    .ok_or_else(|| "--surface-height can't be used with --no-surface".to_string())?
+                    .height = parse_next(&mut args, &arg)?
+            }
+            "--no-bedrock" => options.bedrock = None,
+            "--no-caves" => options.caves = None,
+            "--no-prefabs" => options.prefabs = false,
+            "--tunnel-width" => {
+                cave_settings(&mut options)?.tunnel_width = parse_next(&mut args, &arg)?
+            }
+            "--cave-frequency" => {
+                cave_settings(&mut options)?.frequency = parse_next(&mut args, &arg)?
+            }
+            "--smoothing-steps" => {
+                cave_settings(&mut options)?.smoothing_steps = parse_next(&mut args, &arg)?
+            }
+            "--target-depth" => {
+                cave_settings(&mut options)?.target_depth = parse_next(&mut args, &arg)?
+            }
+            "--min-cave-size" => {
+                cave_settings(&mut options)?.min_cave_size = parse_next(&mut args, &arg)?
+            }
+            "--chunk-borders" => options.chunk_borders = true,
+            "--stats" => options.stats = true,
+            "--simulate" => options.simulate = parse_next(&mut args, &arg)?,
+            "--hashes" => options.hashes = true,
+            "--deltas" => options.deltas = Some(parse_next(&mut args, &arg)?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => return Err(format!("unknown argument: {arg}\n{USAGE}")),
+        }
+    }
+    Ok(options)
+}
+
+const USAGE: &str = "usage: kuilu-worldgen [--seed N] [--region X0 Y0 X1 Y1] [--out FILE] [--no-surface] [--surface-height N] [--no-bedrock] [--no-caves] [--no-prefabs] [--tunnel-width N] [--cave-frequency F] [--smoothing-steps N] [--target-depth N] [--min-cave-size N] [--chunk-borders] [--stats] [--simulate N] [--hashes] [--deltas FILE]";
+
+fn parse_next<T>(args: &mut impl Iterator<Item = String>, name: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = args
+        .next()
+        .ok_or_else(|| format!("missing value for {name}"))?;
+    value
+        .parse()
+        .map_err(|err| format!("invalid value '{value}' for {name}: {err}"))
+}
+
+fn cave_settings(options: &mut Options) -> Result<&mut CaveSettings2D, String> {
+    options
+        .caves
+        .as_mut()
+        .ok_or_else(|| "cave settings can't be used with --no-caves".to_string())
+}
+
+/// Highlight the texels on chunk borders
+fn draw_chunk_borders(region: &Rect2I, data: &mut [u8]) {
+    for (i, pixel) in data.chunks_mut(4).enumerate() {
+        let global = Vector2I {
+            x: region.min.x + i as i32 % region.width(),
+            y: region.max.y - i as i32 / region.width(),
+        };
+        let local = global_to_local(&global);
+        if local.x == 0 || local.y == 0 {
+            pixel.copy_from_slice(&[255, 0, 255, 255]);
+        }
+    }
+}
+
+fn print_stats(terrain: &Terrain2D, region: &Rect2I) {
+    let stats = terrain.material_stats(&TerrainRegion2D::Rect(*region));
+    let total = region.area() as f32;
+    for (id, material) in stats.sorted() {
+        let count = material.count;
+        let name =
+            TexelBehaviour2D::from_id(&id).map_or("empty".to_string(), |b| b.name.to_string());
+        println!(
+            "\tmaterial: {name:<24}id: {id:<8}count: {count:<8}share: {:.1}%",
+            count as f32 / total * 100.0
+        );
+    }
+}
@@ -1,36 +1,78 @@
-use bevy::prelude::*;
+use std::process;
+
+use bevy::{app::ScheduleRunnerPlugin, prelude::*, winit::WinitPlugin};
 use bevy_rapier2d::prelude::*;
 
 use crate::{
     terrain2d::*,
-    util::{frame_counter::FrameCounterPlugin, Vector2I},
+    util::{frame_counter::FrameCounterPlugin, Rect2I, Vector2I},
 };
 
 use self::{
     camera::{GameCameraPlugin, WORLD_WIDTH},
+    console::ConsolePlugin,
     debug::DebugPlugin,
     kinematic::KinematicPlugin,
     player::PlayerPlugin,
+    replay::{ReplayPlugin, ReplayVerdict},
 };
 
 pub mod camera;
+pub mod console;
 pub mod debug;
 pub mod kinematic;
 pub mod player;
+pub mod replay;
 
+/// Start the game. See `replay` for the command line options.
 pub fn init() {
-    App::new()
-        .add_plugins(DefaultPlugins)
-        .add_plugin(FrameCounterPlugin)
+    let replay = match ReplayPlugin::from_args(std::env::args().skip(1)) {
+        Ok(replay) => replay,
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(2);
+        }
+    };
+    let headless = replay.as_ref().map_or(false, |replay| replay.headless());
+    let outcome = replay.as_ref().and_then(|replay| replay.outcome());
+
+    let mut app = App::new();
+    if headless {
+        app.add_plugins(
+            DefaultPlugins
+                .set(WindowPlugin {
+                    add_primary_window: false,
+                    exit_on_all_closed: false,
+                    ..default()
+                })
+                .disable::<WinitPlugin>(),
+        )
+        .add_plugin(ScheduleRunnerPlugin);
+    } else {
+        app.add_plugins(DefaultPlugins);
+    }
+    // Headless replays keep the debug tools and console too, since the recorded input may use them
+    app.add_plugin(FrameCounterPlugin)
         .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
         .add_plugin(Terrain2DPlugin)
         .add_plugin(DebugPlugin)
+        .add_plugin(ConsolePlugin)
         .add_plugin(KinematicPlugin)
         .add_plugin(GameCameraPlugin)
         .add_plugin(PlayerPlugin)
+        .insert_resource(WorldSeed::default())
         .add_startup_system(setup_terrain)
-        .add_startup_system(setup_window)
-        .run();
+        .add_startup_system(setup_window);
+    // After the default seed, which a replay replaces with its own
+    if let Some(replay) = replay {
+        app.add_plugin(replay);
+    }
+    app.run();
+
+    // Headless replays return here, so that a diverged replay fails like a test would
+    if let Some(ReplayVerdict::Diverged(_)) = outcome.and_then(|outcome| outcome.verdict()) {
+        process::exit(1);
+    }
 }
 
 fn setup_window(mut windows: ResMut<Windows>) {
@@ -40,13 +82,27 @@ fn setup_window(mut windows: ResMut<Windows>) {
     }
 }
 
-fn setup_terrain(mut commands: Commands, mut terrain: ResMut<Terrain2D>) {
-    let terrain_gen = TerrainGen2D::new(432678);
-    for y in 0..(WORLD_WIDTH / Chunk2D::SIZE_Y as i32) {
-        for x in 0..(WORLD_WIDTH / Chunk2D::SIZE_X as i32) {
-            let position = Vector2I { x, y };
-            terrain.add_chunk(position, terrain_gen.gen_chunk(&position));
-        }
+/// Seed of the generated world
+#[derive(Resource)]
+pub struct WorldSeed {
+    pub seed: u32,
+}
+
+impl Default for WorldSeed {
+    fn default() -> Self {
+        WorldSeed { seed: 432678 }
+    }
+}
+
+fn setup_terrain(
+    mut commands: Commands,
+    mut terrain: ResMut<Terrain2D>,
+    mut random: ResMut<SimulationRandom2D>,
+    seed: Res<WorldSeed>,
+) {
+    random.reseed(seed.seed as u64);
+    for marker in generate_world(&mut terrain, seed.seed) {
+        commands.spawn(marker_bundle(marker));
     }
 
     commands
@@ -63,3 +119,109 @@ fn setup_terrain(mut commands: Commands, mut terrain: ResMut<Terrain2D>) {
             Transform::from_translation(Vec3::new(WORLD_WIDTH as f32, 0.0, 0.0)),
         ));
 }
+
+/// Generated part of the terrain
+pub fn world_region() -> Rect2I {
+    Rect2I::new(Vector2I::ZERO, Vector2I::ONE * WORLD_WIDTH - Vector2I::ONE)
+}
+
+/// Generate the world region, replacing existing chunks. Returns the markers of the placed prefabs.
+pub fn generate_world(terrain: &mut Terrain2D, seed: u32) -> Vec<PrefabMarker2D> {
+    let region = world_region();
+    let mut terrain_gen = TerrainGen2D::new(seed);
+    terrain_gen.surface = Some(SurfaceSettings2D::default());
+    terrain_gen.bedrock = Some(BedrockSettings2D {
+        bottom: terrain.bottom_boundary.unwrap_or(0),
+        ..default()
+    });
+    terrain_gen.generate_caves(CaveSettings2D::default(), region);
+    terrain_gen.gen_region(terrain, &region);
+    terrain_gen.place_prefabs(terrain, region, &prefab_rules())
+}
+
+pub fn marker_bundle(marker: PrefabMarker2D) -> (Name, TransformBundle, PrefabMarker2D) {
+    (
+        Name::new(format!("Marker {}", marker.name)),
+        TransformBundle::from_transform(Transform::from_translation(Vec3::from(marker.position))),
+        marker,
+    )
+}
+
+pub fn prefab_rules() -> Vec<PrefabRule2D> {
+    use PrefabPaletteEntry2D::*;
+    let palette = [
+        (' ', Transparent),
+        ('.', Texel(Texel2D::EMPTY)),
+        ('#', Texel(13)),
+        ('=', Texel(12)),
+        ('~', Texel(4)),
+        ('L', Marker("loot".into())),
+    ];
+
+    let vault = Prefab2D::from_rows(
+        "vault",
+        &palette,
+        &[
+            "##########",
+            "#........#",
+            "#........#",
+            "#...L....#",
+            "##########",
+        ],
+    )
+    .unwrap();
+
+    let ruin = Prefab2D::from_rows(
+        "ruin",
+        &palette,
+        &[
+            "  =      =  ",
+            " ==      == ",
+            "===  L   ===",
+            "============",
+        ],
+    )
+    .unwrap();
+
+    let pool =
+        Prefab2D::from_rows("pool", &palette, &["=~~~~~~=", "==~~~~==", " ====== "]).unwrap();
+
+    vec![
+        PrefabRule2D {
+            prefab: vault,
+            attempts: 200,
+            max_count: 3,
+            min_y: 0,
+            max_y: WORLD_WIDTH / 2,
+            placement: PrefabPlacement2D::Buried {
+                min_solid_share: 0.9,
+            },
+            mode: StampMode2D::Overwrite,
+            random_transform: false,
+        },
+        PrefabRule2D {
+            prefab: ruin,
+            attempts: 400,
+            max_count: 4,
+            min_y: 0,
+            max_y: WORLD_WIDTH,
+            placement: PrefabPlacement2D::OnFloor {
+                min_open_share: 0.8,
+            },
+            mode: StampMode2D::FillEmpty,
+            random_transform: false,
+        },
+        PrefabRule2D {
+            prefab: pool,
+            attempts: 400,
+            max_count: 4,
+            min_y: 0,
+            max_y: WORLD_WIDTH,
+            placement: PrefabPlacement2D::OnFloor {
+                min_open_share: 0.7,
+            },
+            mode: StampMode2D::Masked,
+            random_transform: true,
+        },
+    ]
+}
@@ -1,6 +1,6 @@
 use bevy::{
     prelude::*,
-    render::camera::{ScalingMode, WindowOrigin},
+    render::camera::{RenderTarget, ScalingMode, WindowOrigin},
 };
 use bevy_inspector_egui::{Inspectable, RegisterInspectable};
 
@@ -14,11 +14,19 @@ impl Plugin for GameCameraPlugin {
     fn build(&self, app: &mut App) {
         app.register_inspectable::<CameraFollow>()
             .register_type::<GameCamera>()
+            .init_resource::<GameCameraSettings>()
             .add_startup_system(camera_setup)
             .add_system_to_stage(CoreStage::PostUpdate, camera_system);
     }
 }
 
+#[derive(Resource, Default)]
+pub struct GameCameraSettings {
+    /// Seconds per frame for the follow movement instead of the frame time, e.g. 1/60 in replays, so that the
+    /// camera and the world positions under the cursor are the same every run
+    pub fixed_timestep: Option<f32>,
+}
+
 #[derive(Clone, Copy, Inspectable, PartialEq, Reflect)]
 pub enum FollowMovement {
     Instant,
@@ -43,6 +51,28 @@ pub struct CameraFollow {
     pub movement: FollowMovement,
 }
 
+/// World position under the cursor, None if the cursor is outside of the camera's window
+pub fn cursor_world_position(
+    windows: &Windows,
+    camera: &Camera,
+    camera_transform: &GlobalTransform,
+) -> Option<Vec2> {
+    // https://bevy-cheatbook.github.io/cookbook/cursor2world.html#2d-games
+    let window = match camera.target {
+        RenderTarget::Window(id) => windows.get(id)?,
+        _ => windows.get_primary()?,
+    };
+    let screen_pos = window.cursor_position()?;
+    let window_size = Vec2::new(window.width() as f32, window.height() as f32);
+
+    // convert screen position [0..resolution] to ndc [-1..1] (gpu coordinates)
+    let ndc = (screen_pos / window_size) * 2.0 - Vec2::ONE;
+
+    // matrix for undoing the projection and camera transform
+    let ndc_to_world = camera_transform.compute_matrix() * camera.projection_matrix().inverse();
+    Some(ndc_to_world.project_point3(ndc.extend(-1.0)).truncate())
+}
+
 fn camera_setup(mut commands: Commands) {
     commands.spawn((
         Name::new("Camera"),
@@ -66,6 +96,7 @@ fn camera_setup(mut commands: Commands) {
 
 fn camera_system(
     time: Res<Time>,
+    settings: Res<GameCameraSettings>,
     mut camera_query: Query<(&mut Transform, &OrthographicProjection), With<Camera2d>>,
     follow_query: Query<(&Transform, &CameraFollow), Without<Camera2d>>,
 ) {
@@ -76,6 +107,7 @@ fn camera_system(
         Some(followed) => followed,
         None => return,
     };
+    let delta_seconds = settings.fixed_timestep.unwrap_or(time.delta_seconds());
 
     for (mut camera_transform, projection) in camera_query.iter_mut() {
         let left_limit = 0.0;
@@ -89,14 +121,14 @@ fn camera_system(
                 camera_transform.translation = move_towards_vec3(
                     camera_transform.translation,
                     target.translation + offset,
-                    speed * time.delta_seconds(),
+                    speed * delta_seconds,
                 );
             }
             FollowMovement::Smooth(speed) => {
                 camera_transform.translation = vec3_lerp(
                     camera_transform.translation,
                     target.translation + offset,
-                    speed * time.delta_seconds(),
+                    speed * delta_seconds,
                 );
             }
         }
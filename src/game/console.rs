@@ -0,0 +1,282 @@
+use std::{collections::VecDeque, mem};
+
+use bevy::prelude::*;
+use bevy_inspector_egui::{
+    bevy_egui::{EguiContext, EguiPlugin},
+    egui::{
+        self,
+        text::{CCursor, CCursorRange},
+    },
+};
+
+mod commands;
+
+pub use commands::*;
+
+/// Developer console, toggled with the backtick key
+pub struct ConsolePlugin;
+
+impl Plugin for ConsolePlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugin(EguiPlugin);
+        }
+        app.insert_resource(Console::default())
+            .init_resource::<ConsoleCommands>()
+            .add_console_command(FillCommand)
+            .add_console_command(ExplodeCommand)
+            .add_console_command(SeedCommand)
+            .add_console_command(TeleportCommand)
+            .add_console_command(SaveCommand)
+            .add_console_command(LoadCommand)
+            .add_console_command(MaterialCommand)
+            .add_console_command(SimCommand)
+            .add_system(console_toggle)
+            .add_system(console_panel.after(console_toggle))
+            .add_system(console_execute.after(console_panel));
+    }
+}
+
+/// Command that can be run from the console. Registered with `AddConsoleCommand::add_console_command`.
+pub trait ConsoleCommand: Send + Sync + 'static {
+    fn name(&self) -> &'static str;
+
+    /// Arguments listed by `help`, e.g. `<x> <y>`
+    fn usage(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    /// Run the command with the arguments following its name. The returned text is printed in the console.
+    fn run(&self, args: &[&str], world: &mut World) -> Result<String, String>;
+
+    /// Candidates for the last argument, which may be partially typed. The console filters them by the typed prefix.
+    fn complete(&self, _args: &[&str]) -> Vec<String> {
+        vec![]
+    }
+}
+
+pub trait AddConsoleCommand {
+    fn add_console_command(&mut self, command: impl ConsoleCommand) -> &mut Self;
+}
+
+impl AddConsoleCommand for App {
+    fn add_console_command(&mut self, command: impl ConsoleCommand) -> &mut Self {
+        self.init_resource::<ConsoleCommands>();
+        self.world
+            .resource_mut::<ConsoleCommands>()
+            .commands
+            .push(Box::new(command));
+        self
+    }
+}
+
+/// Registered console commands
+#[derive(Resource, Default)]
+pub struct ConsoleCommands {
+    commands: Vec<Box<dyn ConsoleCommand>>,
+}
+
+impl ConsoleCommands {
+    pub fn get(&self, name: &str) -> Option<&dyn ConsoleCommand> {
+        self.commands
+            .iter()
+            .find(|command| command.name() == name)
+            .map(|command| command.as_ref())
+    }
+
+    /// Run a line of input. `help` is built in, since it lists the other commands.
+    pub fn run(&self, line: &str, world: &mut World) -> Result<String, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match args.split_first() {
+            Some(split) => split,
+            None => return Ok(String::new()),
+        };
+        if *name == "help" {
+            return Ok(self.help());
+        }
+        match self.get(name) {
+            Some(command) => command.run(args, world),
+            None => Err(format!("unknown command '{name}', see 'help'")),
+        }
+    }
+
+    pub fn help(&self) -> String {
+        let mut lines = vec!["help - List the commands".to_string()];
+        for command in self.commands.iter() {
+            lines.push(format!(
+                "{} {} - {}",
+                command.name(),
+                command.usage(),
+                command.description()
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Completion candidates for the last word of the line, sorted
+    pub fn complete(&self, line: &str) -> Vec<String> {
+        let mut args: Vec<&str> = line.split_whitespace().collect();
+        if line.is_empty() || line.ends_with(char::is_whitespace) {
+            args.push("");
+        }
+        let (partial, mut candidates) = match args.split_first() {
+            Some((name, [])) => (
+                *name,
+                self.commands
+                    .iter()
+                    .map(|command| command.name().to_string())
+                    .chain(["help".to_string()])
+                    .collect(),
+            ),
+            Some((name, rest)) => (
+                rest[rest.len() - 1],
+                self.get(name)
+                    .map_or(vec![], |command| command.complete(rest)),
+            ),
+            None => return vec![],
+        };
+        candidates.retain(|candidate| candidate.starts_with(partial));
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct Console {
+    pub open: bool,
+    input: String,
+    output: VecDeque<String>,
+    /// Lines waiting to be run by `console_execute`
+    queued: Vec<String>,
+    scroll_to_bottom: bool,
+}
+
+impl Console {
+    const MAX_OUTPUT_LINES: usize = 500;
+
+    pub fn print(&mut self, text: &str) {
+        for line in text.lines() {
+            self.output.push_back(line.to_string());
+        }
+        while self.output.len() > Self::MAX_OUTPUT_LINES {
+            self.output.pop_front();
+        }
+        self.scroll_to_bottom = true;
+    }
+
+    /// Queue a line to be run at the end of the frame
+    pub fn execute(&mut self, line: &str) {
+        self.queued.push(line.to_string());
+    }
+
+    /// Complete the last word of the input. Multiple candidates are printed and completed up to their common prefix.
+    fn complete_input(&mut self, commands: &ConsoleCommands) {
+        let candidates = commands.complete(&self.input);
+        let partial_start = self
+            .input
+            .rfind(char::is_whitespace)
+            .map_or(0, |index| index + 1);
+        match candidates.as_slice() {
+            [] => (),
+            [candidate] => {
+                self.input.replace_range(partial_start.., candidate);
+                self.input.push(' ');
+            }
+            _ => {
+                let prefix = common_prefix(&candidates);
+                self.input.replace_range(partial_start.., &prefix);
+                self.print(&candidates.join("  "));
+            }
+        }
+    }
+}
+
+fn common_prefix(words: &[String]) -> String {
+    let mut prefix = words.first().cloned().unwrap_or_default();
+    for word in words.iter().skip(1) {
+        while !word.starts_with(prefix.as_str()) {
+            prefix.pop();
+        }
+    }
+    prefix
+}
+
+fn console_toggle(key_input: Res<Input<KeyCode>>, mut console: ResMut<Console>) {
+    if key_input.just_pressed(KeyCode::Grave) {
+        console.open = !console.open;
+    }
+}
+
+fn console_panel(
+    mut egui_context: ResMut<EguiContext>,
+    mut console: ResMut<Console>,
+    commands: Res<ConsoleCommands>,
+    key_input: Res<Input<KeyCode>>,
+) {
+    if !console.open {
+        return;
+    }
+    // The key that opened the console shouldn't end up in the input
+    console.input.retain(|c| c != '`');
+    let completed = key_input.just_pressed(KeyCode::Tab);
+    if completed {
+        console.complete_input(&commands);
+    }
+
+    let console = &mut *console;
+    egui::Window::new("Console")
+        .default_width(600.0)
+        .show(egui_context.ctx_mut(), |ui| {
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .auto_shrink([false, false])
+                .show(ui, |ui| {
+                    for line in console.output.iter() {
+                        ui.monospace(line);
+                    }
+                    if mem::take(&mut console.scroll_to_bottom) {
+                        ui.scroll_to_cursor(Some(egui::Align::BOTTOM));
+                    }
+                });
+
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut console.input)
+                    .font(egui::TextStyle::Monospace)
+                    .hint_text("help")
+                    .desired_width(f32::INFINITY),
+            );
+            if response.lost_focus() && ui.input().key_pressed(egui::Key::Enter) {
+                let line = mem::take(&mut console.input);
+                console.execute(&line);
+            }
+            // Keep typing in the console, also after Tab has moved the focus
+            response.request_focus();
+            if completed {
+                if let Some(mut state) = egui::TextEdit::load_state(ui.ctx(), response.id) {
+                    let end = CCursor::new(console.input.chars().count());
+                    state.set_ccursor_range(Some(CCursorRange::one(end)));
+                    state.store(ui.ctx(), response.id);
+                }
+            }
+        });
+}
+
+/// Run the queued lines. Exclusive, since commands get access to the whole world.
+fn console_execute(world: &mut World) {
+    let lines = mem::take(&mut world.resource_mut::<Console>().queued);
+    if lines.is_empty() {
+        return;
+    }
+    world.resource_scope(|world, commands: Mut<ConsoleCommands>| {
+        for line in lines {
+            world.resource_mut::<Console>().print(&format!("> {line}"));
+            let result = commands.run(&line, world);
+            let mut console = world.resource_mut::<Console>();
+            match result {
+                Ok(output) => console.print(&output),
+                Err(err) => console.print(&format!("error: {err}")),
+            }
+        }
+    });
+}
@@ -0,0 +1,399 @@
+//! Built-in console commands
+
+use std::{fs, path::PathBuf, str::FromStr};
+
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+
+use super::ConsoleCommand;
+use crate::{
+    game::{generate_world, marker_bundle, player::PlayerInput, world_region, WorldSeed},
+    terrain2d::*,
+    util::{frame_counter::FrameCounter, Rect2I, Vector2I},
+};
+
+/// Directory of the worlds written by `save`
+const SAVE_DIRECTORY: &str = "saves";
+
+fn parse_arg<T: FromStr>(args: &[&str], index: usize, name: &str) -> Result<T, String> {
+    let arg = args
+        .get(index)
+        .ok_or_else(|| format!("missing argument <{name}>"))?;
+    arg.parse().map_err(|_| format!("invalid <{name}> '{arg}'"))
+}
+
+/// Name of the material as typed in the console, with underscores instead of spaces
+pub fn material_console_name(id: &TexelID) -> String {
+    if *id == Texel2D::EMPTY {
+        return "empty".to_string();
+    }
+    TexelBehaviour2D::from_id(id)
+        .map_or(id.to_string(), |behaviour| behaviour.name.replace(' ', "_"))
+}
+
+/// Console names of every material, including empty
+pub fn material_console_names() -> Vec<String> {
+    let mut ids: Vec<TexelID> = TexelBehaviour2D::all().iter().map(|(id, _)| *id).collect();
+    ids.push(Texel2D::EMPTY);
+    ids.iter().map(material_console_name).collect()
+}
+
+/// Material by console name or ID
+pub fn parse_material(arg: &str) -> Result<TexelID, String> {
+    TexelBehaviour2D::all()
+        .iter()
+        .map(|(id, _)| *id)
+        .chain([Texel2D::EMPTY])
+        .find(|id| material_console_name(id).eq_ignore_ascii_case(arg) || id.to_string() == arg)
+        .ok_or_else(|| format!("unknown material '{arg}', see 'material list'"))
+}
+
+/// Path of a save, rejecting names that would point outside the save directory
+fn save_path(name: &str) -> Result<PathBuf, String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(format!(
+            "invalid save name '{name}', use letters, numbers, '_' and '-'"
+        ));
+    }
+    Ok(PathBuf::from(SAVE_DIRECTORY).join(format!("{name}.png")))
+}
+
+/// Apply an edit through the edit history, so that it can be undone in the editor
+fn apply_edit(world: &mut World, edit: &TerrainEdit2D) -> Vec<TexelChange2D> {
+    world.resource_scope(|world, mut history: Mut<TerrainEditHistory2D>| {
+        history.apply_edit(&mut world.resource_mut::<Terrain2D>(), edit)
+    })
+}
+
+pub struct FillCommand;
+
+impl ConsoleCommand for FillCommand {
+    fn name(&self) -> &'static str {
+        "fill"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<x0> <y0> <x1> <y1> <material>"
+    }
+
+    fn description(&self) -> &'static str {
+        "Fill a rectangle with a material"
+    }
+
+    fn run(&self, args: &[&str], world: &mut World) -> Result<String, String> {
+        let from = Vector2I::new(parse_arg(args, 0, "x0")?, parse_arg(args, 1, "y0")?);
+        let to = Vector2I::new(parse_arg(args, 2, "x1")?, parse_arg(args, 3, "y1")?);
+        let id = parse_material(args.get(4).ok_or("missing argument <material>")?)?;
+        let edit = TerrainEdit2D::new(EditShape2D::Rect(Rect2I::from_corners(from, to)), id);
+        let changes = apply_edit(world, &edit);
+        Ok(format!("filled {} texels", changes.len()))
+    }
+
+    fn complete(&self, args: &[&str]) -> Vec<String> {
+        match args.len() {
+            5 => material_console_names(),
+            _ => vec![],
+        }
+    }
+}
+
+pub struct ExplodeCommand;
+
+impl ConsoleCommand for ExplodeCommand {
+    fn name(&self) -> &'static str {
+        "explode"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<x> <y> <radius>"
+    }
+
+    fn description(&self) -> &'static str {
+        "Destroy the texels in a circle, except indestructible ones"
+    }
+
+    fn run(&self, args: &[&str], world: &mut World) -> Result<String, String> {
+        let center = Vector2I::new(parse_arg(args, 0, "x")?, parse_arg(args, 1, "y")?);
+        let radius: f32 = parse_arg(args, 2, "radius")?;
+        let terrain = world.resource::<Terrain2D>();
+        let points = EditShape2D::Circle { center, radius }
+            .points()
+            .into_iter()
+            .filter(|global| {
+                terrain.get_texel(global).map_or(false, |texel| {
+                    texel.id != Texel2D::EMPTY && !TexelBehaviour2D::is_indestructible(&texel.id)
+                })
+            })
+            .collect();
+        let changes = apply_edit(world, &TerrainEdit2D::erase(EditShape2D::Points(points)));
+        Ok(format!("destroyed {} texels", changes.len()))
+    }
+}
+
+pub struct SeedCommand;
+
+impl ConsoleCommand for SeedCommand {
+    fn name(&self) -> &'static str {
+        "seed"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[seed] [regen]"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show or set the world seed. With regen, the world is generated again."
+    }
+
+    fn run(&self, args: &[&str], world: &mut World) -> Result<String, String> {
+        let mut regen = false;
+        for (index, arg) in args.iter().enumerate() {
+            if *arg == "regen" {
+                regen = true;
+            } else {
+                world.resource_mut::<WorldSeed>().seed = parse_arg(args, index, "seed")?;
+            }
+        }
+        let seed = world.resource::<WorldSeed>().seed;
+        if !regen {
+            return Ok(format!("seed: {seed}"));
+        }
+
+        let markers: Vec<Entity> = world
+            .query_filtered::<Entity, With<PrefabMarker2D>>()
+            .iter(world)
+            .collect();
+        for entity in markers {
+            world.despawn(entity);
+        }
+        let mut terrain = world.resource_mut::<Terrain2D>();
+        let indices: Vec<Chunk2DIndex> = terrain.chunk_iter().map(|(index, _)| *index).collect();
+        for index in indices {
+            terrain.remove_chunk(index);
+        }
+        let markers = generate_world(&mut terrain, seed);
+        for marker in markers {
+            world.spawn(marker_bundle(marker));
+        }
+        world
+            .resource_mut::<SimulationRandom2D>()
+            .reseed(seed as u64);
+        world.resource_mut::<TerrainEditHistory2D>().clear();
+        Ok(format!("generated the world with seed {seed}"))
+    }
+
+    fn complete(&self, _args: &[&str]) -> Vec<String> {
+        vec!["regen".to_string()]
+    }
+}
+
+pub struct TeleportCommand;
+
+impl ConsoleCommand for TeleportCommand {
+    fn name(&self) -> &'static str {
+        "tp"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<x> <y>"
+    }
+
+    fn description(&self) -> &'static str {
+        "Move the player"
+    }
+
+    fn run(&self, args: &[&str], world: &mut World) -> Result<String, String> {
+        let position = Vec2::new(parse_arg(args, 0, "x")?, parse_arg(args, 1, "y")?);
+        let mut query =
+            world.query_filtered::<(&mut Transform, Option<&mut Velocity>), With<PlayerInput>>();
+        let (mut transform, velocity) = query
+            .get_single_mut(world)
+            .map_err(|_| "no player to teleport")?;
+        transform.translation = position.extend(transform.translation.z);
+        if let Some(mut velocity) = velocity {
+            *velocity = Velocity::zero();
+        }
+        Ok(format!("teleported to {position}"))
+    }
+}
+
+pub struct SaveCommand;
+
+impl ConsoleCommand for SaveCommand {
+    fn name(&self) -> &'static str {
+        "save"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<name>"
+    }
+
+    fn description(&self) -> &'static str {
+        "Save the materials of the world as a PNG"
+    }
+
+    fn run(&self, args: &[&str], world: &mut World) -> Result<String, String> {
+        let path = save_path(args.first().ok_or("missing argument <name>")?)?;
+        fs::create_dir_all(SAVE_DIRECTORY).map_err(|err| err.to_string())?;
+        world
+            .resource::<Terrain2D>()
+            .export_png(&path, &world_region())
+            .map_err(|err| err.to_string())?;
+        Ok(format!("saved to {}", path.display()))
+    }
+}
+
+pub struct LoadCommand;
+
+impl ConsoleCommand for LoadCommand {
+    fn name(&self) -> &'static str {
+        "load"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<name>"
+    }
+
+    fn description(&self) -> &'static str {
+        "Load a world saved with save"
+    }
+
+    fn run(&self, args: &[&str], world: &mut World) -> Result<String, String> {
+        let path = save_path(args.first().ok_or("missing argument <name>")?)?;
+        world
+            .resource_mut::<Terrain2D>()
+            .import_png(
+                &path,
+                &world_region().min,
+                &TexelPalette2D::from_behaviours(),
+            )
+            .map_err(|err| err.to_string())?;
+        world.resource_mut::<TerrainEditHistory2D>().clear();
+        Ok(format!("loaded {}", path.display()))
+    }
+
+    fn complete(&self, args: &[&str]) -> Vec<String> {
+        if args.len() != 1 {
+            return vec![];
+        }
+        let entries = match fs::read_dir(SAVE_DIRECTORY) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                match path.extension()?.to_str()? {
+                    "png" => Some(path.file_stem()?.to_str()?.to_string()),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+}
+
+pub struct MaterialCommand;
+
+impl ConsoleCommand for MaterialCommand {
+    fn name(&self) -> &'static str {
+        "material"
+    }
+
+    fn usage(&self) -> &'static str {
+        "list"
+    }
+
+    fn description(&self) -> &'static str {
+        "List the materials"
+    }
+
+    fn run(&self, args: &[&str], _world: &mut World) -> Result<String, String> {
+        match args.first() {
+            Some(&"list") => {
+                let mut behaviours = TexelBehaviour2D::all();
+                behaviours.sort_by_key(|(id, _)| *id);
+                let lines: Vec<String> = behaviours
+                    .iter()
+                    .map(|(id, behaviour)| {
+                        format!(
+                            "{id:>3} {:<24} {:?}",
+                            material_console_name(id),
+                            behaviour.form
+                        )
+                    })
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+            _ => Err(format!("usage: {} {}", self.name(), self.usage())),
+        }
+    }
+
+    fn complete(&self, args: &[&str]) -> Vec<String> {
+        match args.len() {
+            1 => vec!["list".to_string()],
+            _ => vec![],
+        }
+    }
+}
+
+pub struct SimCommand;
+
+impl ConsoleCommand for SimCommand {
+    fn name(&self) -> &'static str {
+        "sim"
+    }
+
+    fn usage(&self) -> &'static str {
+        "pause | resume | step [frames] | hash"
+    }
+
+    fn description(&self) -> &'static str {
+        "Pause, resume or step the terrain simulation, or print the hash of its state"
+    }
+
+    fn run(&self, args: &[&str], world: &mut World) -> Result<String, String> {
+        if args.first() == Some(&"hash") {
+            let frame = world.resource::<FrameCounter>().frame;
+            let hash = world.resource::<Terrain2D>().state_hash();
+            return Ok(format!("frame {frame}: {hash:016x}"));
+        }
+        let mut settings = world.resource_mut::<TerrainSimulationSettings2D>();
+        match args.first() {
+            Some(&"pause") => {
+                settings.paused = true;
+                Ok("simulation paused".to_string())
+            }
+            Some(&"resume") => {
+                settings.paused = false;
+                settings.step_frames = 0;
+                match settings.paused_by_editor {
+                    true => Ok("simulation resumes once the editor is closed".to_string()),
+                    false => Ok("simulation resumed".to_string()),
+                }
+            }
+            Some(&"step") => {
+                let frames = match args.get(1) {
+                    Some(_) => parse_arg(args, 1, "frames")?,
+                    None => 1,
+                };
+                settings.paused = true;
+                settings.step_frames += frames;
+                Ok(format!("stepping {frames} frames"))
+            }
+            _ => Err(format!("usage: {} {}", self.name(), self.usage())),
+        }
+    }
+
+    fn complete(&self, args: &[&str]) -> Vec<String> {
+        match args.len() {
+            1 => ["pause", "resume", "step", "hash"]
+                .map(String::from)
+                .to_vec(),
+            _ => vec![],
+        }
+    }
+}
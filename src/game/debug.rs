@@ -1,6 +1,11 @@
 use bevy::prelude::*;
+use bevy_inspector_egui::{
+    bevy_egui::EguiContext, egui, WorldInspectorParams, WorldInspectorPlugin,
+};
 use bevy_prototype_debug_lines::DebugLinesPlugin;
+use bevy_rapier2d::prelude::*;
 
+mod editor;
 mod terrain;
 
 use terrain::TerrainDebugPlugin;
@@ -10,8 +15,74 @@ pub struct DebugPlugin;
 impl Plugin for DebugPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugin(DebugLinesPlugin::default())
-            // .add_plugin(bevy_rapier2d::prelude::RapierDebugRenderPlugin::default())
-            // .add_plugin(bevy_inspector_egui::WorldInspectorPlugin::new())
-            .add_plugin(TerrainDebugPlugin);
+            .add_plugin(TerrainDebugPlugin)
+            .add_plugin(RapierDebugRenderPlugin::default())
+            .add_plugin(WorldInspectorPlugin::new())
+            .insert_resource(DebugSettings::default())
+            .add_system(debug_hotkeys)
+            .add_system(debug_panel.after(debug_hotkeys))
+            .add_system(debug_settings_sync.after(debug_panel));
     }
 }
+
+/// Debug overlays that can be switched on and off at runtime. F2 shows a panel with all of them.
+#[derive(Resource, Default)]
+pub struct DebugSettings {
+    pub panel: bool,
+    pub texel_tooltip: bool,
+    pub dirty_rects: bool,
+    pub chunk_grid: bool,
+    pub active_chunks: bool,
+    /// Print the material counts of every chunk each frame
+    pub chunk_stats: bool,
+    pub colliders: bool,
+    pub world_inspector: bool,
+    /// Graphs of `TerrainMetrics`
+    pub profiler: bool,
+}
+
+impl DebugSettings {
+    /// Name, hotkey and flag of every overlay
+    fn toggles(&mut self) -> [(&'static str, KeyCode, &mut bool); 9] {
+        [
+            ("Debug panel", KeyCode::F2, &mut self.panel),
+            ("Texel tooltip", KeyCode::F3, &mut self.texel_tooltip),
+            ("Dirty rects", KeyCode::F4, &mut self.dirty_rects),
+            ("Chunk grid", KeyCode::F5, &mut self.chunk_grid),
+            ("Active chunks", KeyCode::F6, &mut self.active_chunks),
+            ("Chunk stats", KeyCode::F7, &mut self.chunk_stats),
+            ("Collider outlines", KeyCode::F8, &mut self.colliders),
+            ("World inspector", KeyCode::F9, &mut self.world_inspector),
+            ("Terrain profiler", KeyCode::F10, &mut self.profiler),
+        ]
+    }
+}
+
+fn debug_hotkeys(key_input: Res<Input<KeyCode>>, mut settings: ResMut<DebugSettings>) {
+    for (_, key, enabled) in settings.toggles() {
+        if key_input.just_pressed(key) {
+            *enabled = !*enabled;
+        }
+    }
+}
+
+fn debug_panel(mut egui_context: ResMut<EguiContext>, mut settings: ResMut<DebugSettings>) {
+    if !settings.panel {
+        return;
+    }
+    egui::Window::new("Debug").show(egui_context.ctx_mut(), |ui| {
+        for (name, key, enabled) in settings.toggles() {
+            ui.checkbox(enabled, format!("{name} ({key:?})"));
+        }
+    });
+}
+
+/// Apply the settings to the overlays of other plugins
+fn debug_settings_sync(
+    settings: Res<DebugSettings>,
+    mut rapier_debug: ResMut<DebugRenderContext>,
+    mut inspector: ResMut<WorldInspectorParams>,
+) {
+    rapier_debug.enabled = settings.colliders;
+    inspector.enabled = settings.world_inspector;
+}
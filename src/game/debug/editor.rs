@@ -0,0 +1,431 @@
+use std::collections::HashSet;
+
+use bevy::{input::mouse::MouseWheel, prelude::*};
+use bevy_inspector_egui::{
+    bevy_egui::{EguiContext, EguiPlugin},
+    egui,
+};
+use bevy_prototype_debug_lines::DebugLines;
+
+use super::terrain::draw_box;
+use crate::{
+    game::camera::{cursor_world_position, GameCamera},
+    terrain2d::*,
+    util::{raster, Rect2I, Vector2I},
+};
+
+/// In-game terrain editor, toggled with F1. The simulation is paused while editing.
+pub struct EditorPlugin;
+
+impl Plugin for EditorPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugin(EguiPlugin);
+        }
+        app.insert_resource(TerrainBrush2D::default())
+            .insert_resource(EditorState::default())
+            .add_system(editor_toggle)
+            .add_system(editor_panel.after(editor_toggle))
+            .add_system(editor_tools.after(editor_panel));
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BrushShape {
+    #[default]
+    Circle,
+    Square,
+}
+
+#[derive(Resource)]
+pub struct TerrainBrush2D {
+    pub radius: i32,
+    pub tile: TexelID,
+    pub shape: BrushShape,
+    pub mode: EditMode2D,
+}
+
+impl Default for TerrainBrush2D {
+    fn default() -> Self {
+        TerrainBrush2D {
+            radius: 3,
+            tile: 7,
+            shape: BrushShape::Circle,
+            mode: EditMode2D::Replace,
+        }
+    }
+}
+
+impl TerrainBrush2D {
+    pub fn shape_at(&self, center: Vector2I) -> EditShape2D {
+        let extent = self.radius - 1;
+        match self.shape {
+            BrushShape::Circle => EditShape2D::Circle {
+                center,
+                radius: extent as f32,
+            },
+            BrushShape::Square => EditShape2D::Rect(Rect2I::new(
+                center - Vector2I::ONE * extent,
+                center + Vector2I::ONE * extent,
+            )),
+        }
+    }
+
+    /// Fill the shape with the brush material, or empty it
+    pub fn edit(&self, shape: EditShape2D, erase: bool) -> TerrainEdit2D {
+        if erase {
+            TerrainEdit2D::erase(shape)
+        } else {
+            TerrainEdit2D::new(shape, self.tile).with_mode(self.mode)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EditorTool {
+    #[default]
+    Brush,
+    Fill,
+    Line,
+    Rect,
+    Eyedropper,
+    Select,
+    Paste,
+}
+
+impl EditorTool {
+    const ALL: [(EditorTool, &'static str); 7] = [
+        (EditorTool::Brush, "Brush"),
+        (EditorTool::Fill, "Fill"),
+        (EditorTool::Line, "Line"),
+        (EditorTool::Rect, "Rectangle"),
+        (EditorTool::Eyedropper, "Eyedropper"),
+        (EditorTool::Select, "Select"),
+        (EditorTool::Paste, "Paste"),
+    ];
+}
+
+#[derive(Resource)]
+pub struct EditorState {
+    pub enabled: bool,
+    pub tool: EditorTool,
+    pub line_width: f32,
+    /// Maximum number of texels replaced by a single fill
+    pub fill_limit: usize,
+    pub selection: Option<Rect2I>,
+    pub clipboard: Option<TerrainClipboard2D>,
+    /// Brush position on the previous frame, so that fast strokes don't leave gaps
+    last_position: Option<Vector2I>,
+    /// Where the current line, rectangle or selection drag started
+    drag_start: Option<Vector2I>,
+}
+
+impl Default for EditorState {
+    fn default() -> Self {
+        EditorState {
+            enabled: false,
+            tool: EditorTool::Brush,
+            line_width: 1.0,
+            fill_limit: 50_000,
+            selection: None,
+            clipboard: None,
+            last_position: None,
+            drag_start: None,
+        }
+    }
+}
+
+fn editor_toggle(
+    key_input: Res<Input<KeyCode>>,
+    mut editor: ResMut<EditorState>,
+    mut simulation: ResMut<TerrainSimulationSettings2D>,
+    mut history: ResMut<TerrainEditHistory2D>,
+) {
+    if key_input.just_pressed(KeyCode::F1) {
+        editor.enabled = !editor.enabled;
+        editor.last_position = None;
+        editor.drag_start = None;
+        simulation.paused_by_editor = editor.enabled;
+        history.end_stroke();
+    }
+}
+
+fn editor_panel(
+    mut egui_context: ResMut<EguiContext>,
+    mut editor: ResMut<EditorState>,
+    mut brush: ResMut<TerrainBrush2D>,
+    mut terrain: ResMut<Terrain2D>,
+    mut history: ResMut<TerrainEditHistory2D>,
+) {
+    if !editor.enabled {
+        return;
+    }
+
+    let mut materials = vec![(Texel2D::EMPTY, "empty".to_string(), egui::Color32::GRAY)];
+    materials.extend(TexelBehaviour2D::all().into_iter().map(|(id, behaviour)| {
+        let [r, g, b, _] = color_to_rgba8(behaviour.color);
+        (
+            id,
+            behaviour.name.to_string(),
+            egui::Color32::from_rgb(r, g, b),
+        )
+    }));
+    let material_name = |id: TexelID| {
+        materials
+            .iter()
+            .find(|(material, _, _)| *material == id)
+            .map_or(format!("#{id}"), |(_, name, _)| name.clone())
+    };
+
+    egui::Window::new("Editor").show(egui_context.ctx_mut(), |ui| {
+        ui.label("F1 closes the editor. The simulation is paused while editing.");
+        ui.horizontal_wrapped(|ui| {
+            for (tool, name) in EditorTool::ALL {
+                ui.selectable_value(&mut editor.tool, tool, name);
+            }
+        });
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut brush.shape, BrushShape::Circle, "Circle");
+            ui.radio_value(&mut brush.shape, BrushShape::Square, "Square");
+        });
+        ui.add(egui::Slider::new(&mut brush.radius, 1..=128).text("Brush radius"));
+        ui.add(egui::Slider::new(&mut editor.line_width, 1.0..=32.0).text("Line width"));
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut brush.mode, EditMode2D::Replace, "Replace");
+            ui.radio_value(&mut brush.mode, EditMode2D::OnlyEmpty, "Only empty");
+            let only_material = matches!(brush.mode, EditMode2D::OnlyMaterial(_));
+            if ui.radio(only_material, "Only material").clicked() && !only_material {
+                brush.mode = EditMode2D::OnlyMaterial(brush.tile);
+            }
+        });
+        if let EditMode2D::OnlyMaterial(target) = &mut brush.mode {
+            egui::ComboBox::from_label("Replaced material")
+                .selected_text(material_name(*target))
+                .show_ui(ui, |ui| {
+                    for (id, name, _) in materials.iter() {
+                        ui.selectable_value(target, *id, name.as_str());
+                    }
+                });
+        }
+
+        ui.separator();
+        egui::ScrollArea::vertical()
+            .max_height(200.0)
+            .show(ui, |ui| {
+                for (id, name, color) in materials.iter() {
+                    ui.selectable_value(
+                        &mut brush.tile,
+                        *id,
+                        egui::RichText::new(format!("{id:>3} {name}")).color(*color),
+                    );
+                }
+            });
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(history.can_undo(), egui::Button::new("Undo"))
+                .clicked()
+            {
+                history.undo(&mut terrain);
+            }
+            if ui
+                .add_enabled(history.can_redo(), egui::Button::new("Redo"))
+                .clicked()
+            {
+                history.redo(&mut terrain);
+            }
+        });
+        if let Some(selection) = editor.selection {
+            ui.horizontal(|ui| {
+                ui.label(format!("Selection {selection}"));
+                if ui.button("Copy").clicked() {
+                    editor.clipboard = Some(terrain.copy_region(&selection));
+                }
+            });
+        }
+        if let Some(clipboard) = &editor.clipboard {
+            ui.label(format!(
+                "Clipboard {}x{}",
+                clipboard.size.x, clipboard.size.y
+            ));
+        }
+    });
+}
+
+fn editor_tools(
+    mut egui_context: ResMut<EguiContext>,
+    mut editor: ResMut<EditorState>,
+    mut brush: ResMut<TerrainBrush2D>,
+    mut terrain: ResMut<Terrain2D>,
+    mut history: ResMut<TerrainEditHistory2D>,
+    mut debug_draw: ResMut<DebugLines>,
+    windows: Res<Windows>,
+    mouse_input: Res<Input<MouseButton>>,
+    key_input: Res<Input<KeyCode>>,
+    mut mouse_wheel: EventReader<MouseWheel>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<GameCamera>>,
+) {
+    if !editor.enabled {
+        mouse_wheel.clear();
+        return;
+    }
+
+    // Undo with Ctrl+Z, redo with Ctrl+Y or Ctrl+Shift+Z, copy the selection with Ctrl+C and paste with Ctrl+V
+    if key_input.pressed(KeyCode::LControl) && !egui_context.ctx_mut().wants_keyboard_input() {
+        let shift = key_input.pressed(KeyCode::LShift);
+        if key_input.just_pressed(KeyCode::Z) && !shift {
+            history.undo(&mut terrain);
+        } else if key_input.just_pressed(KeyCode::Y)
+            || (key_input.just_pressed(KeyCode::Z) && shift)
+        {
+            history.redo(&mut terrain);
+        }
+        if key_input.just_pressed(KeyCode::C) {
+            if let Some(selection) = editor.selection {
+                editor.clipboard = Some(terrain.copy_region(&selection));
+            }
+        }
+        if key_input.just_pressed(KeyCode::V) && editor.clipboard.is_some() {
+            editor.tool = EditorTool::Paste;
+        }
+    }
+
+    // Clicks on the editor panel are not meant for the terrain
+    if egui_context.ctx_mut().wants_pointer_input() {
+        mouse_wheel.clear();
+        return;
+    }
+
+    for event in mouse_wheel.iter() {
+        brush.radius = (brush.radius + event.y.round() as i32).clamp(1, 128);
+    }
+
+    let (camera, camera_transform) = camera_query.single();
+    let position = match cursor_world_position(&windows, camera, camera_transform) {
+        Some(world_pos) => Vector2I::from(world_pos),
+        None => return,
+    };
+
+    let buttons = [MouseButton::Left, MouseButton::Right];
+    let erase =
+        mouse_input.pressed(MouseButton::Right) || mouse_input.just_released(MouseButton::Right);
+    let color = TexelBehaviour2D::from_id(&brush.tile)
+        .map_or(Color::rgba(0.0, 0.0, 0.0, 0.0), |tb| tb.color);
+
+    match editor.tool {
+        EditorTool::Brush => {
+            draw_points(&mut debug_draw, &brush.shape_at(position).points(), color);
+            if mouse_input.any_just_pressed(buttons) {
+                // Everything painted while a button is held is undone at once
+                history.begin_stroke();
+                editor.last_position = None;
+            }
+            if mouse_input.any_pressed(buttons) {
+                let from = editor.last_position.unwrap_or(position);
+                let points: HashSet<Vector2I> = raster::line(from, position)
+                    .into_iter()
+                    .flat_map(|point| brush.shape_at(point).points())
+                    .collect();
+                history.apply_edit(
+                    &mut terrain,
+                    &brush.edit(EditShape2D::Points(points.into_iter().collect()), erase),
+                );
+                editor.last_position = Some(position);
+            }
+            if mouse_input.any_just_released(buttons) {
+                history.end_stroke();
+                editor.last_position = None;
+            }
+        }
+        EditorTool::Fill => {
+            if mouse_input.any_just_pressed(buttons) {
+                let id = terrain.get_texel(&position).unwrap_or_default().id;
+                let fill = terrain.flood_fill(&position, &[id], editor.fill_limit);
+                history.apply_edit(
+                    &mut terrain,
+                    &brush.edit(EditShape2D::Points(fill.texels), erase),
+                );
+            }
+        }
+        EditorTool::Line | EditorTool::Rect | EditorTool::Select => {
+            if mouse_input.any_just_pressed(buttons) {
+                editor.drag_start = Some(position);
+            }
+            let start = match editor.drag_start {
+                Some(start) => start,
+                None => return,
+            };
+            let rect = Rect2I::from_corners(start, position);
+            let shape = match editor.tool {
+                EditorTool::Line => EditShape2D::Line {
+                    from: start,
+                    to: position,
+                    width: editor.line_width,
+                },
+                _ => EditShape2D::Rect(rect),
+            };
+            match editor.tool {
+                EditorTool::Line => draw_points(&mut debug_draw, &shape.points(), color),
+                _ => draw_rect(&mut debug_draw, &rect, color),
+            }
+            if mouse_input.any_just_released(buttons) {
+                editor.drag_start = None;
+                if editor.tool == EditorTool::Select {
+                    editor.selection = Some(rect);
+                } else {
+                    history.apply_edit(&mut terrain, &brush.edit(shape, erase));
+                }
+            }
+        }
+        EditorTool::Eyedropper => {
+            if mouse_input.just_pressed(MouseButton::Left) {
+                brush.tile = terrain.get_texel(&position).unwrap_or_default().id;
+            }
+        }
+        EditorTool::Paste => {
+            let clipboard = match &editor.clipboard {
+                Some(clipboard) => clipboard,
+                None => return,
+            };
+            draw_rect(
+                &mut debug_draw,
+                &Rect2I::new(position, position + clipboard.size - Vector2I::ONE),
+                Color::WHITE,
+            );
+            if mouse_input.just_pressed(MouseButton::Left) {
+                let changes = terrain.with_change_cause(TexelChangeCause2D::Edit, |terrain| {
+                    terrain.set_texels(clipboard.texels_at(position))
+                });
+                history.record(&changes);
+            }
+        }
+    }
+
+    if let Some(selection) = editor.selection {
+        draw_rect(&mut debug_draw, &selection, Color::YELLOW);
+    }
+}
+
+fn draw_points(debug_draw: &mut DebugLines, points: &[Vector2I], color: Color) {
+    for pos in points.iter() {
+        debug_draw.line_colored(
+            Vec3::from(*pos) + Vec3::new(0.45, 0.45, 1.0),
+            Vec3::from(*pos) + Vec3::new(0.55, 0.55, 1.0),
+            0.0,
+            color,
+        );
+    }
+}
+
+fn draw_rect(debug_draw: &mut DebugLines, rect: &Rect2I, color: Color) {
+    draw_box(
+        debug_draw,
+        Vec3::from(rect.min) + Vec3::Z,
+        Vec3::from(rect.max + Vector2I::ONE) + Vec3::Z,
+        color,
+        0.0,
+    );
+}
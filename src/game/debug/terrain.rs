@@ -1,147 +1,217 @@
-use std::collections::HashMap;
-
-use crate::{game::camera::GameCamera, terrain2d::*, util::Vector2I};
-use bevy::{input::mouse::MouseWheel, prelude::*, render::camera::RenderTarget};
+use crate::{
+    game::camera::{cursor_world_position, GameCamera},
+    terrain2d::*,
+    util::{Rect2I, Vector2I},
+};
+use bevy::prelude::*;
+use bevy_inspector_egui::{
+    bevy_egui::EguiContext,
+    egui::{
+        self,
+        plot::{Legend, Line, Plot, PlotPoints},
+    },
+};
 use bevy_prototype_debug_lines::DebugLines;
 
+use super::{
+    editor::{EditorPlugin, TerrainBrush2D},
+    DebugSettings,
+};
+
 pub struct TerrainDebugPlugin;
 
 impl Plugin for TerrainDebugPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(TerrainBrush2D::default())
-            // .add_system_to_stage(TerrainStages::EventHandler, dirty_rect_visualizer)
-            // .add_system_to_stage(CoreStage::Last, chunk_debugger)
-            .add_system(debug_painter);
+        app.add_plugin(EditorPlugin)
+            .add_system(texel_tooltip)
+            .add_system(terrain_metrics_panel)
+            .add_system_to_stage(TerrainStages::EventHandler, dirty_rect_visualizer)
+            .add_system_to_stage(CoreStage::Last, chunk_grid_visualizer)
+            .add_system_to_stage(CoreStage::Last, active_chunk_visualizer)
+            .add_system_to_stage(CoreStage::Last, chunk_debugger);
     }
 }
 
-#[derive(Resource)]
-struct TerrainBrush2D {
-    pub radius: i32,
-    pub tile: TexelID,
-}
-
-impl Default for TerrainBrush2D {
-    fn default() -> Self {
-        TerrainBrush2D { radius: 3, tile: 7 }
-    }
-}
-
-// REM: Dirty and hopefully temporary
-fn debug_painter(
-    mut terrain: ResMut<Terrain2D>,
-    mut debug_draw: ResMut<DebugLines>,
+/// Hover tooltip describing the texel under the cursor.
+/// Pressing E copies the material of the texel into the brush.
+fn texel_tooltip(
+    settings: Res<DebugSettings>,
+    mut egui_context: ResMut<EguiContext>,
     mut brush: ResMut<TerrainBrush2D>,
+    terrain: Res<Terrain2D>,
     windows: Res<Windows>,
-    mouse_input: Res<Input<MouseButton>>,
     key_input: Res<Input<KeyCode>>,
-    mut mouse_wheel: EventReader<MouseWheel>,
     camera_query: Query<(&Camera, &GlobalTransform), With<GameCamera>>,
 ) {
-    // let allow_painting = key_input.pressed(KeyCode::LControl);
-    let allow_painting = true;
-
-    // Change brush
-    for event in mouse_wheel.iter() {
-        if allow_painting {
-            brush.radius = (brush.radius + event.y.round() as i32).clamp(1, 128);
-        }
-    }
-
-    if !allow_painting {
+    if !settings.texel_tooltip {
         return;
     }
 
-    // https://bevy-cheatbook.github.io/cookbook/cursor2world.html#2d-games
-    // get the camera info and transform
-    // assuming there is exactly one main camera entity, so query::single() is OK
     let (camera, camera_transform) = camera_query.single();
-
-    // get the window that the camera is displaying to (or the primary window)
-    let window = if let RenderTarget::Window(id) = camera.target {
-        windows.get(id).unwrap()
-    } else {
-        windows.get_primary().unwrap()
+    let global = match cursor_world_position(&windows, camera, camera_transform) {
+        Some(world_pos) => Vector2I::from(world_pos),
+        None => return,
     };
+    let ctx = egui_context.ctx_mut();
+    if ctx.is_pointer_over_area() {
+        return;
+    }
 
-    // check if the cursor is inside the window and get its position
-    let world_pos = if let Some(screen_pos) = window.cursor_position() {
-        // get the size of the window
-        let window_size = Vec2::new(window.width() as f32, window.height() as f32);
-
-        // convert screen position [0..resolution] to ndc [-1..1] (gpu coordinates)
-        let ndc = (screen_pos / window_size) * 2.0 - Vec2::ONE;
-
-        // matrix for undoing the projection and camera transform
-        let ndc_to_world = camera_transform.compute_matrix() * camera.projection_matrix().inverse();
+    let (texel, behaviour) = terrain.get_texel_behaviour(&global);
+    if key_input.just_pressed(KeyCode::E) && !ctx.wants_keyboard_input() {
+        if let Some(texel) = texel {
+            brush.tile = texel.id;
+        }
+    }
 
-        // use it to convert ndc to world-space coordinates
-        let world_pos = ndc_to_world.project_point3(ndc.extend(-1.0));
+    let chunk_index = global_to_chunk_index(&global);
+    let chunk = terrain.index_to_chunk(&chunk_index);
+    egui::show_tooltip_at_pointer(ctx, egui::Id::new("texel_tooltip"), |ui| {
+        match (texel, behaviour) {
+            _ if !terrain.is_within_boundaries(&global) => {
+                ui.label("Out of bounds");
+            }
+            (Some(texel), behaviour) => {
+                let name = behaviour.map_or("empty".to_string(), |b| b.name.to_string());
+                ui.label(format!("{name} (id {})", texel.id));
+                ui.label(format!("Density: {}", texel.density));
+            }
+            (None, _) => {
+                ui.label("Unloaded");
+            }
+        }
+        if let Some(frame) = terrain.get_latest_simulation(&global) {
+            ui.label(format!("Simulation frame: {frame}"));
+        }
+        ui.label(format!("Global: {global}"));
+        ui.label(format!(
+            "Chunk: {chunk_index}, local: {}",
+            global_to_local(&global)
+        ));
+        ui.label(match chunk.and_then(|chunk| chunk.dirty_rect) {
+            Some(rect) => format!("Dirty: {} - {}", rect.min, rect.max),
+            None => "Clean".to_string(),
+        });
+    });
+}
 
-        // reduce it to a 2D value
-        world_pos.truncate()
-    } else {
+/// Rolling graphs of the terrain metrics, with a button to export them
+fn terrain_metrics_panel(
+    settings: Res<DebugSettings>,
+    mut egui_context: ResMut<EguiContext>,
+    mut metrics: ResMut<TerrainMetrics>,
+    mut export_status: Local<Option<String>>,
+) {
+    if !settings.profiler {
         return;
-    };
+    }
 
-    for (index, key) in vec![
-        KeyCode::Key1,
-        KeyCode::Key2,
-        KeyCode::Key3,
-        KeyCode::Key4,
-        KeyCode::Key5,
-        KeyCode::Key6,
-        KeyCode::Key7,
-        KeyCode::Key8,
-        KeyCode::Key9,
-    ]
-    .iter()
-    .enumerate()
-    {
-        if key_input.just_pressed(*key) {
-            brush.tile = index as u8 + 1;
-        }
+    fn series(
+        metrics: &TerrainMetrics,
+        name: &str,
+        value: impl Fn(&TerrainFrameMetrics) -> f64,
+    ) -> Line {
+        let points: PlotPoints = metrics
+            .history()
+            .map(|frame| [frame.frame as f64, value(frame)])
+            .collect();
+        Line::new(points).name(name)
     }
 
-    let origin = Vector2I::from(world_pos);
-    let radius = brush.radius;
-    let id = match (
-        mouse_input.pressed(MouseButton::Left),
-        mouse_input.pressed(MouseButton::Right),
-    ) {
-        (true, false) => brush.tile,
-        (_, _) => 0,
-    };
-    let color = TexelBehaviour2D::from_id(&brush.tile)
-        .map_or(Color::rgba(0.0, 0.0, 0.0, 0.0), |tb| tb.color);
-
-    for y in origin.y - (radius - 1)..origin.y + radius {
-        for x in origin.x - (radius - 1)..origin.x + radius {
-            let dx = (x - origin.x).abs();
-            let dy = (y - origin.y).abs();
-            let draw = dx * dx + dy * dy <= (radius - 1) * (radius - 1);
-
-            if draw {
-                let pos: Vector2I = Vector2I { x, y };
-                debug_draw.line_colored(
-                    Vec3::from(pos) + Vec3::new(0.45, 0.45, 1.0),
-                    Vec3::from(pos) + Vec3::new(0.55, 0.55, 1.0),
-                    0.0,
-                    color,
-                );
-                if mouse_input.pressed(MouseButton::Left) || mouse_input.pressed(MouseButton::Right)
-                {
-                    terrain.set_texel(&pos, Texel2D { id, ..default() }, None)
-                }
+    egui::Window::new("Terrain profiler").show(egui_context.ctx_mut(), |ui| {
+        let latest = metrics.latest().copied().unwrap_or_default();
+        let average = metrics.average();
+        egui::Grid::new("terrain_metrics").show(ui, |ui| {
+            ui.label("");
+            ui.label("Latest");
+            ui.label("Average");
+            ui.end_row();
+            let rows: [(&str, fn(&TerrainFrameMetrics) -> String); 8] = [
+                ("Simulation", |m| format!("{:.2} ms", m.simulation_ms)),
+                ("Gas dispersion", |m| {
+                    format!("{:.2} ms", m.gas_dispersion_ms)
+                }),
+                ("Sprite sync", |m| format!("{:.2} ms", m.sprite_sync_ms)),
+                ("Collision sync", |m| {
+                    format!("{:.2} ms", m.collision_sync_ms)
+                }),
+                ("Total", |m| format!("{:.2} ms", m.total_ms())),
+                ("Active chunks", |m| m.active_chunks.to_string()),
+                ("Simulated texels", |m| m.simulated_texels.to_string()),
+                ("Collider rebuilds", |m| m.collider_rebuilds.to_string()),
+            ];
+            for (name, format) in rows {
+                ui.label(name);
+                ui.label(format(&latest));
+                ui.label(format(&average));
+                ui.end_row();
+            }
+        });
+
+        ui.label("Timings (ms)");
+        Plot::new("terrain_metrics_timings")
+            .height(120.0)
+            .legend(Legend::default())
+            .show(ui, |plot_ui| {
+                plot_ui.line(series(&metrics, "Simulation", |m| m.simulation_ms as f64));
+                plot_ui.line(series(&metrics, "Gas dispersion", |m| {
+                    m.gas_dispersion_ms as f64
+                }));
+                plot_ui.line(series(&metrics, "Sprite sync", |m| m.sprite_sync_ms as f64));
+                plot_ui.line(series(&metrics, "Collision sync", |m| {
+                    m.collision_sync_ms as f64
+                }));
+            });
+        ui.label("Simulated texels");
+        Plot::new("terrain_metrics_texels")
+            .height(80.0)
+            .show(ui, |plot_ui| {
+                plot_ui.line(series(&metrics, "Simulated texels", |m| {
+                    m.simulated_texels as f64
+                }));
+            });
+        ui.label("Chunks");
+        Plot::new("terrain_metrics_chunks")
+            .height(80.0)
+            .legend(Legend::default())
+            .show(ui, |plot_ui| {
+                plot_ui.line(series(&metrics, "Active chunks", |m| {
+                    m.active_chunks as f64
+                }));
+                plot_ui.line(series(&metrics, "Collider rebuilds", |m| {
+                    m.collider_rebuilds as f64
+                }));
+            });
+
+        ui.horizontal(|ui| {
+            if ui.button("Export CSV").clicked() {
+                let path = "terrain_metrics.csv";
+                *export_status = Some(match metrics.export_csv(path) {
+                    Ok(()) => format!("Exported to {path}"),
+                    Err(err) => format!("Export failed: {err}"),
+                });
             }
+            if ui.button("Clear").clicked() {
+                metrics.clear();
+            }
+        });
+        if let Some(status) = export_status.as_ref() {
+            ui.label(status.as_str());
         }
-    }
+    });
 }
 
 /**
     Visualize dirty rects
 */
-fn dirty_rect_visualizer(terrain: Res<Terrain2D>, mut debug_draw: ResMut<DebugLines>) {
+fn dirty_rect_visualizer(
+    settings: Res<DebugSettings>,
+    terrain: Res<Terrain2D>,
+    mut debug_draw: ResMut<DebugLines>,
+) {
+    if !settings.dirty_rects {
+        return;
+    }
     for (chunk_index, chunk) in terrain.chunk_iter() {
         let rect = if let Some(rect) = chunk.dirty_rect {
             rect
@@ -156,55 +226,76 @@ fn dirty_rect_visualizer(terrain: Res<Terrain2D>, mut debug_draw: ResMut<DebugLi
     }
 }
 
-fn chunk_debugger(terrain: Res<Terrain2D>, mut debug_draw: ResMut<DebugLines>) {
-    for (chunk_index, chunk) in terrain.chunk_iter() {
-        println!("chunk contents: {chunk_index:?}");
-        let offset = Vec3::from(chunk_index_to_global(chunk_index));
-        let min = offset + Vec3::ZERO;
-        let max = offset + Vec3::from(Chunk2D::SIZE);
-        draw_box(
+/**
+    Visualize chunk borders
+*/
+fn chunk_grid_visualizer(
+    settings: Res<DebugSettings>,
+    terrain: Res<Terrain2D>,
+    mut debug_draw: ResMut<DebugLines>,
+) {
+    if !settings.chunk_grid {
+        return;
+    }
+    for (chunk_index, _) in terrain.chunk_iter() {
+        draw_chunk(
             &mut debug_draw,
-            min,
-            max,
+            chunk_index,
             Color::rgba(0.5, 0.0, 0.5, 0.5),
-            0.0,
         );
+    }
+}
 
-        let mut tile_counter: HashMap<TexelID, (u32, u32)> = HashMap::new();
-        for y in 0..Chunk2D::SIZE_Y as i32 {
-            for x in 0..Chunk2D::SIZE_X as i32 {
-                let local = Vector2I::new(x, y);
-                let global = local_to_global(&local, chunk_index);
-                if let (Some(texel), _) = terrain.get_texel_behaviour(&global) {
-                    if !tile_counter.contains_key(&texel.id) {
-                        tile_counter.insert(texel.id, (0, 0));
-                    }
-                    let (old_count, old_density) = tile_counter[&texel.id].clone();
-                    tile_counter.insert(
-                        texel.id,
-                        (old_count + 1, old_density + texel.density as u32),
-                    );
-                }
-            }
+/**
+    Visualize chunks that are simulated, i.e. have a dirty rect
+*/
+fn active_chunk_visualizer(
+    settings: Res<DebugSettings>,
+    terrain: Res<Terrain2D>,
+    mut debug_draw: ResMut<DebugLines>,
+) {
+    if !settings.active_chunks {
+        return;
+    }
+    for (chunk_index, chunk) in terrain.chunk_iter() {
+        if chunk.dirty_rect.is_some() {
+            draw_chunk(&mut debug_draw, chunk_index, Color::GREEN);
         }
+    }
+}
 
-        let mut counts: Vec<(u8, String, u32, u32)> = vec![];
-
-        for (id, (count, total_density)) in tile_counter.iter() {
-            let name =
-                TexelBehaviour2D::from_id(id).map_or("unknown".to_string(), |b| b.name.to_string());
-            counts.push((*id, name, *count, *total_density));
-        }
-        counts.sort_unstable_by_key(|c| c.0);
-        for (id, name, count, total_density) in counts {
+/**
+    Print the material counts of every chunk
+*/
+fn chunk_debugger(settings: Res<DebugSettings>, terrain: Res<Terrain2D>) {
+    if !settings.chunk_stats {
+        return;
+    }
+    for (chunk_index, _) in terrain.chunk_iter() {
+        println!("chunk contents: {chunk_index:?}");
+        let rect = Rect2I::new(
+            chunk_index_to_global(chunk_index),
+            chunk_index_to_global(chunk_index) + Chunk2D::SIZE - Vector2I::ONE,
+        );
+        let stats = terrain.material_stats(&TerrainRegion2D::Rect(rect));
+        for (id, material) in stats.sorted() {
+            let name = TexelBehaviour2D::from_id(&id)
+                .map_or("unknown".to_string(), |b| b.name.to_string());
             println!(
-                "\tmaterial: {name:<24}id: {id:<8}count: {count:<8}total_density: {total_density:<8}"
+                "\tmaterial: {name:<24}id: {id:<8}count: {:<8}total_density: {:<8}",
+                material.count, material.total_density
             );
         }
     }
 }
 
-fn draw_box(debug_draw: &mut DebugLines, min: Vec3, max: Vec3, color: Color, duration: f32) {
+fn draw_chunk(debug_draw: &mut DebugLines, chunk_index: &Chunk2DIndex, color: Color) {
+    let min = Vec3::from(chunk_index_to_global(chunk_index));
+    let max = min + Vec3::from(Chunk2D::SIZE);
+    draw_box(debug_draw, min, max, color, 0.0);
+}
+
+pub fn draw_box(debug_draw: &mut DebugLines, min: Vec3, max: Vec3, color: Color, duration: f32) {
     let points = vec![
         Vec3::new(min.x, min.y, min.z),
         Vec3::new(max.x, min.y, min.z),
@@ -12,6 +12,7 @@ impl Plugin for KinematicPlugin {
         app.register_type::<KinematicState>()
             .register_type::<KinematicProperties>()
             .register_type::<KinematicInput>()
+            .register_type::<OneWayCollider>()
             .add_system(kinematic_movement);
     }
 }
@@ -45,6 +46,12 @@ pub struct KinematicState {
     #[reflect(ignore)]
     pub last_move: Option<MoveShapeOutput>,
     pub did_jump: bool,
+    /// Friction coefficient of the ground, None when in the air
+    #[reflect(ignore)]
+    pub ground_friction: Option<f32>,
+    /// Upwards speed for the next step, after landing on a surface with restitution
+    #[reflect(ignore)]
+    pub bounce: Option<f32>,
 }
 
 impl KinematicState {
@@ -100,6 +107,8 @@ fn kinematic_movement(
     )>,
     shape_query: Query<&Collider, Without<Sensor>>,
     child_query: Query<&Children>,
+    surface_query: Query<(Option<&Friction>, Option<&Restitution>)>,
+    one_way_query: Query<(), With<OneWayCollider>>,
     mut rapier_context: ResMut<RapierContext>,
 ) {
     let dt = rapier_context.integration_parameters.dt;
@@ -116,15 +125,21 @@ fn kinematic_movement(
         let default = &KinematicInput::default();
         let input = input.unwrap_or(default);
 
-        let (speed, acceleration, friction) = if kinematic_state
+        let was_grounded = kinematic_state
             .last_move
             .as_ref()
-            .map_or(false, |last| last.grounded)
-        {
+            .map_or(false, |last| last.grounded);
+        let (speed, acceleration, friction) = if was_grounded {
+            // Slippery surfaces make it harder to both speed up and slow down
+            let surface = kinematic_state
+                .ground_friction
+                .map_or(1.0, |ground_friction| {
+                    ground_friction / Friction::default().coefficient
+                });
             (
                 props.ground_speed,
-                props.ground_acceleration,
-                props.ground_friction,
+                props.ground_acceleration * surface,
+                props.ground_friction * surface,
             )
         } else {
             (props.air_speed, props.air_acceleration, props.air_friction)
@@ -132,6 +147,8 @@ fn kinematic_movement(
 
         const GRAVITY_DIR: Vec2 = Vec2::NEG_Y;
         const GRAVITY_COEFFICIENT: f32 = 2.0;
+        const GROUND_PROBE_DISTANCE: f32 = 1.0;
+        const MIN_BOUNCE_SPEED: f32 = 30.0;
 
         let current_velocity = kinematic_state
             .last_move
@@ -190,6 +207,11 @@ fn kinematic_movement(
             kinematic_state.did_jump = true;
         }
 
+        if let Some(bounce) = kinematic_state.bounce.take() {
+            velocity.y = bounce;
+            kinematic_state.did_jump = true;
+        }
+
         let shape = if let Ok(shape) = shape_query.get(entity) {
             Some(shape)
         } else if let Ok(children) = child_query.get(entity) {
@@ -226,12 +248,37 @@ fn kinematic_movement(
                 ..MoveShapeOptions::default()
             };
 
+            let rotation = rotation.to_euler(EulerRot::ZYX).0;
+
+            // One-way colliders only block from above. They are ignored while moving up, or while still
+            // overlapping them after jumping through.
+            let mut overlapping_one_way: Vec<Entity> = vec![];
+            if velocity.y <= 0.0 {
+                let mut overlap_filter = QueryFilter::new();
+                let is_one_way = |coll_entity| one_way_query.contains(coll_entity);
+                overlap_filter.predicate = Some(&is_one_way);
+                rapier_context.intersections_with_shape(
+                    translation.truncate(),
+                    rotation,
+                    shape,
+                    overlap_filter,
+                    |coll_entity| {
+                        overlapping_one_way.push(coll_entity);
+                        true
+                    },
+                );
+            }
+
             let mut filter = QueryFilter::new();
-            let predicate = |coll_entity| coll_entity != entity;
+            let predicate = |coll_entity| {
+                coll_entity != entity
+                    && !(one_way_query.contains(coll_entity)
+                        && (velocity.y > 0.0 || overlapping_one_way.contains(&coll_entity)))
+            };
             filter.predicate = Some(&predicate);
 
             if let Some(collision_groups) = collision_groups {
-                filter.groups(InteractionGroups::new(
+                filter = filter.groups(InteractionGroups::new(
                     bevy_rapier2d::rapier::geometry::Group::from_bits_truncate(
                         collision_groups.memberships.bits(),
                     ),
@@ -245,7 +292,7 @@ fn kinematic_movement(
                 velocity * dt,
                 shape,
                 translation.truncate(),
-                rotation.to_euler(EulerRot::ZYX).0,
+                rotation,
                 shape.raw.0.mass_properties(1.0).mass(),
                 move_options,
                 filter,
@@ -255,6 +302,31 @@ fn kinematic_movement(
             // Apply movement
             transform.translation += last_move.effective_translation.extend(0.0);
 
+            // Check the surface below
+            let ground = if last_move.grounded {
+                rapier_context
+                    .cast_shape(
+                        translation.truncate() + last_move.effective_translation,
+                        rotation,
+                        GRAVITY_DIR,
+                        shape,
+                        GROUND_PROBE_DISTANCE,
+                        filter,
+                    )
+                    .and_then(|(ground, _)| surface_query.get(ground).ok())
+            } else {
+                None
+            };
+            kinematic_state.ground_friction = ground.map(|(friction, _)| {
+                friction.map_or(Friction::default().coefficient, |f| f.coefficient)
+            });
+            let restitution = ground
+                .and_then(|(_, restitution)| restitution)
+                .map_or(0.0, |r| r.coefficient);
+            if !was_grounded && restitution > 0.0 && velocity.y < -MIN_BOUNCE_SPEED {
+                kinematic_state.bounce = Some(-velocity.y * restitution);
+            }
+
             Some(last_move)
         } else {
             None
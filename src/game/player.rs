@@ -1,6 +1,9 @@
 use bevy::prelude::*;
+use bevy_inspector_egui::bevy_egui::EguiContext;
 use bevy_rapier2d::prelude::*;
 
+use crate::util::CollisionLayers;
+
 use super::{
     camera::{CameraFollow, FollowMovement},
     kinematic::*,
@@ -29,6 +32,7 @@ pub struct PlayerBundle {
 
 pub fn player_system(
     input: Res<Input<KeyCode>>,
+    egui_context: Option<ResMut<EguiContext>>,
     mut query: Query<(&mut KinematicInput, &Transform), With<PlayerInput>>,
 ) {
     let (mut kinematic_input, _transform) = match query.get_single_mut() {
@@ -36,6 +40,15 @@ pub fn player_system(
         Err(_) => return,
     };
 
+    // Typing in the console or other text fields doesn't move the player
+    if egui_context.map_or(false, |mut egui_context| {
+        egui_context.ctx_mut().wants_keyboard_input()
+    }) {
+        kinematic_input.movement = Vec2::ZERO;
+        kinematic_input.want_jump = false;
+        return;
+    }
+
     let movement = Vec2 {
         x: input_to_axis(input.pressed(KeyCode::A), input.pressed(KeyCode::D)),
         // x: -1.0,
@@ -61,7 +74,7 @@ fn input_to_axis(negative: bool, positive: bool) -> f32 {
 pub fn player_spawn(mut commands: Commands) {
     let kinematic = KinematicBundle {
         transform: TransformBundle::from_transform(Transform::from_translation(Vec3::new(
-            256.0, 128.0, 0.0,
+            256.0, 480.0, 0.0,
         ))),
         properties: KinematicProperties {
             gravity: None,
@@ -82,9 +95,10 @@ pub fn player_spawn(mut commands: Commands) {
             ..default()
         })
         .insert(TransformBundle::from_transform(Transform::from_xyz(
-            256.0, 128.0, 0.0,
+            256.0, 480.0, 0.0,
         )))
         .insert(Collider::cuboid(3.0, 6.0))
+        .insert(CollisionGroups::new(CollisionLayers::PLAYER, Group::ALL))
         .insert(PlayerBundle {
             kinematic,
             ..default()
@@ -0,0 +1,610 @@
+//! Input recording and replay.
+//!
+//! A replay stores the world seed and the input events of every tick, i.e. `FrameCounter::frame`, from the start of
+//! the game. Hashes of the terrain are stored every `HASH_INTERVAL` ticks, so a replay also reports when the game
+//! no longer behaves as it did while recording. Started from the command line:
+//!
+//! ```text
+//! kuilu --record FILE
+//! kuilu --replay FILE [--headless]
+//! ```
+//!
+//! Visual replays are driven by the recorded events, so the mouse and keyboard should be left alone while watching.
+//! The window is resized to the recorded size, since it decides which world position is under the cursor. Headless
+//! replays use a window that is never shown, so the editor and console work the same, and exit once the replay ends,
+//! with status 1 if the terrain diverged from the recording.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use bevy::{
+    app::AppExit,
+    input::{
+        keyboard::KeyboardInput,
+        mouse::{MouseButtonInput, MouseScrollUnit, MouseWheel},
+        ButtonState, InputSystem,
+    },
+    prelude::*,
+    reflect::{DynamicEnum, DynamicVariant, TypeInfo, Typed},
+    window::{WindowCreated, WindowId, WindowResized},
+};
+use bevy_rapier2d::prelude::*;
+
+use super::{camera::GameCameraSettings, WorldSeed};
+use crate::{terrain2d::*, util::frame_counter::FrameCounter};
+
+/// Ticks between the recorded terrain hashes
+pub const HASH_INTERVAL: u64 = 60;
+
+const HEADER: &str = "kuilu-replay 1";
+
+/// Input event of a replay
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplayInput {
+    Key(KeyboardInput),
+    MouseButton(MouseButtonInput),
+    /// Cursor position in logical pixels from the bottom-left corner of the window, as in `CursorMoved`
+    Cursor(Vec2),
+    MouseWheel(MouseWheel),
+    Character(char),
+    /// Logical size of the primary window
+    WindowSize(Vec2),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Replay {
+    pub seed: u32,
+    /// Inputs in the order of their ticks
+    pub inputs: Vec<(u64, ReplayInput)>,
+    /// Terrain state hashes at the end of the ticks, see `Terrain2D::state_hash`
+    pub hashes: BTreeMap<u64, u64>,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    /// Line numbers start from 1
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(err) => write!(f, "io error: {err}"),
+            ReplayError::Parse { line, message } => write!(f, "line {line}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<io::Error> for ReplayError {
+    fn from(err: io::Error) -> Self {
+        ReplayError::Io(err)
+    }
+}
+
+impl Replay {
+    /// Last tick with an input or a hash
+    pub fn last_tick(&self) -> u64 {
+        let input = self.inputs.last().map_or(0, |(tick, _)| *tick);
+        let hash = self.hashes.keys().last().copied().unwrap_or(0);
+        input.max(hash)
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Replay, ReplayError> {
+        Replay::read_from(BufReader::new(File::open(path)?))
+    }
+
+    /// Read the text format written by `ReplayRecorder`. Each line after the header and seed is a tick followed by
+    /// an entry, e.g. `120 key press 30 A`.
+    pub fn read_from(reader: impl BufRead) -> Result<Replay, ReplayError> {
+        let mut replay = Replay::default();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let parse_error = |message: String| ReplayError::Parse {
+                line: index + 1,
+                message,
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match (index, words.as_slice()) {
+                (_, []) => continue,
+                (0, _) if line.trim() == HEADER => (),
+                (0, _) => return Err(parse_error(format!("expected '{HEADER}'"))),
+                (_, ["seed", seed]) => replay.seed = parse_word(seed).map_err(parse_error)?,
+                (_, [tick, "hash", hash]) => {
+                    let tick = parse_word(tick).map_err(parse_error)?;
+                    let hash = u64::from_str_radix(hash, 16)
+                        .map_err(|_| parse_error(format!("invalid hash '{hash}'")))?;
+                    replay.hashes.insert(tick, hash);
+                }
+                (_, [tick, entry @ ..]) => {
+                    let tick: u64 = parse_word(tick).map_err(parse_error)?;
+                    if replay.inputs.last().map_or(false, |(last, _)| tick < *last) {
+                        return Err(parse_error(format!("tick {tick} is out of order")));
+                    }
+                    let input = ReplayInput::parse(entry).map_err(parse_error)?;
+                    replay.inputs.push((tick, input));
+                }
+            }
+        }
+        Ok(replay)
+    }
+}
+
+fn parse_word<T: FromStr>(word: &str) -> Result<T, String> {
+    word.parse().map_err(|_| format!("invalid value '{word}'"))
+}
+
+fn parse_state(word: &str) -> Result<ButtonState, String> {
+    match word {
+        "press" => Ok(ButtonState::Pressed),
+        "release" => Ok(ButtonState::Released),
+        _ => Err(format!("invalid button state '{word}'")),
+    }
+}
+
+fn state_name(state: ButtonState) -> &'static str {
+    match state {
+        ButtonState::Pressed => "press",
+        ButtonState::Released => "release",
+    }
+}
+
+/// Key code by the name of its variant, as written by `Debug`
+fn parse_key_code(name: &str) -> Result<KeyCode, String> {
+    match KeyCode::type_info() {
+        TypeInfo::Enum(info) if info.variant(name).is_some() => {
+            let mut key_code = KeyCode::Key1;
+            key_code.apply(&DynamicEnum::new(
+                KeyCode::type_name(),
+                name,
+                DynamicVariant::Unit,
+            ));
+            Ok(key_code)
+        }
+        _ => Err(format!("invalid key code '{name}'")),
+    }
+}
+
+impl ReplayInput {
+    fn parse(words: &[&str]) -> Result<ReplayInput, String> {
+        match words {
+            ["key", state, scan_code, key_code] => Ok(ReplayInput::Key(KeyboardInput {
+                scan_code: parse_word(scan_code)?,
+                key_code: match *key_code {
+                    "-" => None,
+                    name => Some(parse_key_code(name)?),
+                },
+                state: parse_state(state)?,
+            })),
+            ["mouse", state, button] => Ok(ReplayInput::MouseButton(MouseButtonInput {
+                button: match *button {
+                    "left" => MouseButton::Left,
+                    "right" => MouseButton::Right,
+                    "middle" => MouseButton::Middle,
+                    other => MouseButton::Other(parse_word(other)?),
+                },
+                state: parse_state(state)?,
+            })),
+            ["cursor", x, y] => Ok(ReplayInput::Cursor(Vec2::new(
+                parse_word(x)?,
+                parse_word(y)?,
+            ))),
+            ["wheel", unit, x, y] => Ok(ReplayInput::MouseWheel(MouseWheel {
+                unit: match *unit {
+                    "line" => MouseScrollUnit::Line,
+                    "pixel" => MouseScrollUnit::Pixel,
+                    _ => return Err(format!("invalid scroll unit '{unit}'")),
+                },
+                x: parse_word(x)?,
+                y: parse_word(y)?,
+            })),
+            ["window", width, height] => Ok(ReplayInput::WindowSize(Vec2::new(
+                parse_word(width)?,
+                parse_word(height)?,
+            ))),
+            ["char", code] => parse_word(code)
+                .ok()
+                .and_then(char::from_u32)
+                .map(ReplayInput::Character)
+                .ok_or_else(|| format!("invalid character code '{code}'")),
+            _ => Err(format!("invalid entry '{}'", words.join(" "))),
+        }
+    }
+}
+
+impl fmt::Display for ReplayInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayInput::Key(input) => {
+                write!(f, "key {} {} ", state_name(input.state), input.scan_code)?;
+                match input.key_code {
+                    Some(key_code) => write!(f, "{key_code:?}"),
+                    None => write!(f, "-"),
+                }
+            }
+            ReplayInput::MouseButton(input) => {
+                write!(f, "mouse {} ", state_name(input.state))?;
+                match input.button {
+                    MouseButton::Left => write!(f, "left"),
+                    MouseButton::Right => write!(f, "right"),
+                    MouseButton::Middle => write!(f, "middle"),
+                    MouseButton::Other(button) => write!(f, "{button}"),
+                }
+            }
+            ReplayInput::Cursor(position) => write!(f, "cursor {} {}", position.x, position.y),
+            ReplayInput::MouseWheel(wheel) => {
+                let unit = match wheel.unit {
+                    MouseScrollUnit::Line => "line",
+                    MouseScrollUnit::Pixel => "pixel",
+                };
+                write!(f, "wheel {unit} {} {}", wheel.x, wheel.y)
+            }
+            ReplayInput::Character(c) => write!(f, "char {}", *c as u32),
+            ReplayInput::WindowSize(size) => write!(f, "window {} {}", size.x, size.y),
+        }
+    }
+}
+
+/// Writes the input to a replay file as it happens, so that the replay survives a crash
+#[derive(Resource)]
+pub struct ReplayRecorder {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+}
+
+impl ReplayRecorder {
+    pub fn create(path: impl AsRef<Path>, seed: u32) -> io::Result<ReplayRecorder> {
+        let mut writer = BufWriter::new(File::create(&path)?);
+        writeln!(writer, "{HEADER}")?;
+        writeln!(writer, "seed {seed}")?;
+        Ok(ReplayRecorder {
+            path: path.as_ref().to_path_buf(),
+            writer: Some(writer),
+        })
+    }
+
+    /// Write a line. Recording stops on the first error.
+    fn write(&mut self, tick: u64, entry: impl fmt::Display) {
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => return,
+        };
+        if let Err(err) = writeln!(writer, "{tick} {entry}") {
+            error!("stopped recording to {}: {err}", self.path.display());
+            self.writer = None;
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(Err(err)) = self.writer.as_mut().map(|writer| writer.flush()) {
+            error!("stopped recording to {}: {err}", self.path.display());
+            self.writer = None;
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct ReplayPlayer {
+    pub replay: Replay,
+    /// Index of the next input to send
+    next_input: usize,
+    /// First tick where the terrain hash didn't match the recording
+    pub diverged: Option<u64>,
+    pub finished: bool,
+    pub headless: bool,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay, headless: bool) -> ReplayPlayer {
+        ReplayPlayer {
+            replay,
+            next_input: 0,
+            diverged: None,
+            finished: false,
+            headless,
+        }
+    }
+}
+
+/// How a finished replay compared with the recording
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayVerdict {
+    Matched,
+    /// First tick where the terrain hash didn't match
+    Diverged(u64),
+}
+
+/// Verdict of a replay, shared with the caller of `App::run`, which consumes the app
+#[derive(Resource, Clone, Default)]
+pub struct ReplayOutcome(Arc<Mutex<Option<ReplayVerdict>>>);
+
+impl ReplayOutcome {
+    /// None until the replay has finished
+    pub fn verdict(&self) -> Option<ReplayVerdict> {
+        *self.0.lock().unwrap()
+    }
+
+    fn set(&self, verdict: ReplayVerdict) {
+        *self.0.lock().unwrap() = Some(verdict);
+    }
+}
+
+pub enum ReplayPlugin {
+    Record(PathBuf),
+    Play {
+        replay: Replay,
+        headless: bool,
+        outcome: ReplayOutcome,
+    },
+}
+
+impl ReplayPlugin {
+    /// Replay options from the command line. None if neither `--record` nor `--replay` is given.
+    pub fn from_args(
+        mut args: impl Iterator<Item = String>,
+    ) -> Result<Option<ReplayPlugin>, String> {
+        let mut record = None;
+        let mut replay = None;
+        let mut headless = false;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--record" => record = Some(args.next().ok_or("missing file for --record")?),
+                "--replay" => replay = Some(args.next().ok_or("missing file for --replay")?),
+                "--headless" => headless = true,
+                _ => return Err(format!("unknown argument: {arg}\n{USAGE}")),
+            }
+        }
+        match (record, replay) {
+            (Some(_), Some(_)) => Err("--record and --replay can't be used together".to_string()),
+            (Some(_), None) if headless => Err("--headless requires --replay".to_string()),
+            (Some(path), None) => Ok(Some(ReplayPlugin::Record(PathBuf::from(path)))),
+            (None, Some(path)) => {
+                let replay = Replay::read(&path).map_err(|err| format!("{path}: {err}"))?;
+                Ok(Some(ReplayPlugin::Play {
+                    replay,
+                    headless,
+                    outcome: ReplayOutcome::default(),
+                }))
+            }
+            (None, None) if headless => Err("--headless requires --replay".to_string()),
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub fn headless(&self) -> bool {
+        matches!(self, ReplayPlugin::Play { headless: true, .. })
+    }
+
+    /// Verdict of a played replay, to read once the app has exited
+    pub fn outcome(&self) -> Option<ReplayOutcome> {
+        match self {
+            ReplayPlugin::Play { outcome, .. } => Some(outcome.clone()),
+            ReplayPlugin::Record(_) => None,
+        }
+    }
+}
+
+const USAGE: &str = "usage: kuilu [--record FILE | --replay FILE [--headless]]";
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_startup_system(replay_setup);
+        match self {
+            ReplayPlugin::Record(path) => {
+                let seed = app
+                    .world
+                    .get_resource::<WorldSeed>()
+                    .map_or(0, |seed| seed.seed);
+                match ReplayRecorder::create(path, seed) {
+                    Ok(recorder) => {
+                        app.insert_resource(recorder)
+                            .add_system_to_stage(CoreStage::PreUpdate, replay_record)
+                            .add_system_to_stage(CoreStage::Last, replay_record_hash);
+                    }
+                    Err(err) => error!("could not record to {}: {err}", path.display()),
+                }
+            }
+            ReplayPlugin::Play {
+                replay,
+                headless,
+                outcome,
+            } => {
+                if *headless {
+                    add_headless_window(app);
+                }
+                app.insert_resource(WorldSeed { seed: replay.seed })
+                    .insert_resource(outcome.clone())
+                    .insert_resource(ReplayPlayer::new(replay.clone(), *headless))
+                    .add_system_to_stage(CoreStage::PreUpdate, replay_play.before(InputSystem))
+                    .add_system_to_stage(CoreStage::Last, replay_verify);
+            }
+        }
+    }
+}
+
+/// Primary window of headless replays. It has no surface, so it is never shown or rendered to.
+fn add_headless_window(app: &mut App) {
+    let id = WindowId::primary();
+    let descriptor = WindowDescriptor {
+        width: 900.0,
+        height: 450.0,
+        ..default()
+    };
+    let window = Window::new(id, &descriptor, 900, 450, 1.0, None, None);
+    app.world.resource_mut::<Windows>().add(window);
+    app.world
+        .resource_mut::<Events<WindowCreated>>()
+        .send(WindowCreated { id });
+}
+
+/// Take out the sources of nondeterminism: frame times and background collider tasks
+fn replay_setup(
+    mut rapier_config: ResMut<RapierConfiguration>,
+    mut collider_settings: ResMut<ChunkColliderSettings2D>,
+    mut camera_settings: ResMut<GameCameraSettings>,
+) {
+    rapier_config.timestep_mode = TimestepMode::Fixed {
+        dt: 1.0 / 60.0,
+        substeps: 1,
+    };
+    collider_settings.blocking = true;
+    camera_settings.fixed_timestep = Some(1.0 / 60.0);
+}
+
+fn replay_record(
+    mut recorder: ResMut<ReplayRecorder>,
+    frame_counter: Res<FrameCounter>,
+    windows: Res<Windows>,
+    // Latest recorded window size
+    mut window_size: Local<Option<Vec2>>,
+    mut keyboard_events: EventReader<KeyboardInput>,
+    mut mouse_button_events: EventReader<MouseButtonInput>,
+    mut cursor_events: EventReader<CursorMoved>,
+    mut mouse_wheel_events: EventReader<MouseWheel>,
+    mut character_events: EventReader<ReceivedCharacter>,
+) {
+    let tick = frame_counter.frame;
+    if let Some(window) = windows.get_primary() {
+        let size = Vec2::new(window.width(), window.height());
+        if *window_size != Some(size) {
+            *window_size = Some(size);
+            recorder.write(tick, ReplayInput::WindowSize(size));
+        }
+    }
+    for event in keyboard_events.iter() {
+        recorder.write(tick, ReplayInput::Key(event.clone()));
+    }
+    for event in mouse_button_events.iter() {
+        recorder.write(tick, ReplayInput::MouseButton(event.clone()));
+    }
+    for event in cursor_events.iter() {
+        recorder.write(tick, ReplayInput::Cursor(event.position));
+    }
+    for event in mouse_wheel_events.iter() {
+        recorder.write(tick, ReplayInput::MouseWheel(event.clone()));
+    }
+    for event in character_events.iter() {
+        recorder.write(tick, ReplayInput::Character(event.char));
+    }
+}
+
+fn replay_record_hash(
+    mut recorder: ResMut<ReplayRecorder>,
+    frame_counter: Res<FrameCounter>,
+    terrain: Res<Terrain2D>,
+) {
+    let tick = frame_counter.frame;
+    if tick % HASH_INTERVAL == 0 {
+        recorder.write(tick, format!("hash {:016x}", terrain.state_hash()));
+    }
+    recorder.flush();
+}
+
+/// Send the recorded input of the tick as events, before the input systems handle them
+fn replay_play(
+    mut player: ResMut<ReplayPlayer>,
+    frame_counter: Res<FrameCounter>,
+    mut windows: ResMut<Windows>,
+    mut keyboard_events: EventWriter<KeyboardInput>,
+    mut mouse_button_events: EventWriter<MouseButtonInput>,
+    mut cursor_events: EventWriter<CursorMoved>,
+    mut mouse_wheel_events: EventWriter<MouseWheel>,
+    mut character_events: EventWriter<ReceivedCharacter>,
+    mut resize_events: EventWriter<WindowResized>,
+) {
+    let tick = frame_counter.frame;
+    let player = &mut *player;
+    while let Some((input_tick, input)) = player.replay.inputs.get(player.next_input) {
+        if *input_tick > tick {
+            break;
+        }
+        player.next_input += 1;
+        match input {
+            ReplayInput::Key(event) => keyboard_events.send(event.clone()),
+            ReplayInput::MouseButton(event) => mouse_button_events.send(event.clone()),
+            ReplayInput::Cursor(position) => {
+                // The window keeps its own cursor position, which is what the game reads
+                if let Some(window) = windows.get_primary_mut() {
+                    let physical = (*position * window.scale_factor() as f32).as_dvec2();
+                    window.update_cursor_physical_position_from_backend(Some(physical));
+                }
+                cursor_events.send(CursorMoved {
+                    id: WindowId::primary(),
+                    position: *position,
+                });
+            }
+            ReplayInput::MouseWheel(event) => mouse_wheel_events.send(event.clone()),
+            ReplayInput::Character(c) => character_events.send(ReceivedCharacter {
+                id: WindowId::primary(),
+                char: *c,
+            }),
+            ReplayInput::WindowSize(size) => {
+                let window = match windows.get_primary_mut() {
+                    Some(window) => window,
+                    None => continue,
+                };
+                if player.headless {
+                    // Nothing else resizes the headless window, which has a scale factor of one
+                    window.update_actual_size_from_backend(size.x as u32, size.y as u32);
+                    resize_events.send(WindowResized {
+                        id: WindowId::primary(),
+                        width: size.x,
+                        height: size.y,
+                    });
+                } else {
+                    window.set_resolution(size.x, size.y);
+                }
+            }
+        }
+    }
+}
+
+/// Compare the terrain with the recorded hashes, and report the result at the end of the replay
+fn replay_verify(
+    mut player: ResMut<ReplayPlayer>,
+    outcome: Res<ReplayOutcome>,
+    frame_counter: Res<FrameCounter>,
+    terrain: Res<Terrain2D>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if player.finished {
+        return;
+    }
+    let tick = frame_counter.frame;
+    if let Some(expected) = player.replay.hashes.get(&tick).copied() {
+        if player.diverged.is_none() && terrain.state_hash() != expected {
+            warn!("replay diverged at tick {tick}");
+            player.diverged = Some(tick);
+        }
+    }
+    if tick < player.replay.last_tick() {
+        return;
+    }
+
+    player.finished = true;
+    match player.diverged {
+        Some(diverged) => {
+            error!("replay finished at tick {tick}, diverged at tick {diverged}");
+            outcome.set(ReplayVerdict::Diverged(diverged));
+        }
+        None => {
+            info!("replay finished at tick {tick}, terrain hashes matched");
+            outcome.set(ReplayVerdict::Matched);
+        }
+    }
+    if player.headless {
+        exit.send(AppExit);
+    }
+}
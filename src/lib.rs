@@ -0,0 +1,3 @@
+pub mod game;
+pub mod terrain2d;
+pub mod util;
@@ -1,7 +1,3 @@
-pub mod game;
-pub mod terrain2d;
-pub mod util;
-
 fn main() {
-    game::init();
+    kuilu::game::init();
 }
@@ -1,25 +1,48 @@
-use std::collections::{
-    hash_map::{Iter, IterMut},
-    HashMap,
+use std::{
+    collections::{
+        hash_map::{Iter, IterMut},
+        HashMap,
+    },
+    time::Instant,
 };
 
 use bevy::ecs::prelude::SystemStage;
 use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
 
+mod cave_gen2d;
 mod chunk2d;
+mod delta_stream2d;
+mod edit_history2d;
+mod prefab2d;
+mod raycast2d;
+mod region_query2d;
+mod simulation2d;
+mod terrain_edit2d;
 mod terrain_gen2d;
+mod terrain_image2d;
+mod terrain_metrics2d;
 mod texel2d;
 mod texel_behaviour2d;
 
+pub use cave_gen2d::*;
 pub use chunk2d::*;
+pub use delta_stream2d::*;
+pub use edit_history2d::*;
+pub use prefab2d::*;
+pub use raycast2d::*;
+pub use region_query2d::*;
+pub use simulation2d::*;
+pub use terrain_edit2d::*;
 pub use terrain_gen2d::*;
+pub use terrain_image2d::*;
+pub use terrain_metrics2d::*;
 pub use texel2d::*;
 pub use texel_behaviour2d::*;
 
 use crate::{
     game::camera::WORLD_WIDTH,
-    util::{frame_counter::FrameCounter, math::*, Vector2I},
+    util::{frame_counter::FrameCounter, math::*, Rect2I, Vector2I},
 };
 
 pub struct Terrain2DPlugin;
@@ -45,13 +68,20 @@ impl Plugin for Terrain2DPlugin {
         );
 
         app.register_type::<TerrainChunk2D>()
+            .register_type::<PrefabMarker2D>()
             .insert_resource(Terrain2D::new(
                 Some(WORLD_WIDTH * 2),
                 Some(0),
                 Some(0),
                 Some(WORLD_WIDTH),
             ))
+            .insert_resource(ChunkColliderSettings2D::default())
+            .insert_resource(TerrainSimulationSettings2D::default())
+            .insert_resource(SimulationRandom2D::default())
+            .insert_resource(TerrainEditHistory2D::default())
+            .insert_resource(TerrainMetrics::default())
             .add_event::<TerrainEvent2D>()
+            .add_event::<TerrainDelta2D>()
             .add_system_to_stage(TerrainStages::Simulation, terrain_simulation)
             .add_system_to_stage(TerrainStages::EventHandler, emit_terrain_events)
             .add_system_to_stage(
@@ -60,7 +90,9 @@ impl Plugin for Terrain2DPlugin {
                 chunk_spawner.before(emit_terrain_events),
             )
             .add_system_to_stage(TerrainStages::ChunkSync, chunk_sprite_sync)
-            .add_system_to_stage(CoreStage::PostUpdate, chunk_collision_sync);
+            .add_system_to_stage(TerrainStages::ChunkSync, edit_history_validation)
+            .add_system_to_stage(CoreStage::PostUpdate, chunk_collision_sync)
+            .add_system_to_stage(CoreStage::Last, terrain_metrics_frame);
     }
 }
 
@@ -74,236 +106,71 @@ pub enum TerrainStages {
     ChunkSync,
 }
 
-fn terrain_simulation(mut terrain: ResMut<Terrain2D>, frame_counter: Res<FrameCounter>) {
-    let simulation_frame = (frame_counter.frame % u8::MAX as u64) as u8 + 1;
-
-    let indices = terrain
-        .chunk_iter()
-        .map(|(chunk_index, _)| *chunk_index)
-        .collect::<Vec<Chunk2DIndex>>()
-        .clone();
-
-    for chunk_index in indices.iter() {
-        // Mark few chunks dirty in interval. Should help activate stale chunks
-        if let Some(chunk) = terrain.index_to_chunk_mut(&chunk_index) {
-            let interval = 1;
-            if frame_counter.frame % interval == 0 {
-                let i = ((frame_counter.frame / interval) % 100) as i32;
-                if (chunk_index.y % 10) * 10 + (chunk_index.x % 10) == i {
-                    chunk.mark_all_dirty();
-                }
-            }
-        };
-
-        if let Some(rect) = &terrain
-            .index_to_chunk(&chunk_index)
-            .map_or(None, |chunk| chunk.dirty_rect.clone())
-        {
-            if let Some(chunk) = terrain.index_to_chunk_mut(&chunk_index) {
-                chunk.mark_clean();
-            } else {
-                continue;
-            };
-
-            // Texel simulation
-            let mut y_range: Vec<_> = (rect.min.y..rect.max.y + 1).collect();
-            let mut x_range: Vec<_> = (rect.min.x..rect.max.x + 1).collect();
-            if frame_counter.frame % 2 == 0 {
-                y_range.reverse();
-            }
-            if frame_counter.frame / 2 % 2 == 0 {
-                x_range.reverse();
-            }
-
-            for y in y_range.iter() {
-                for x in x_range.iter() {
-                    let local = Vector2I::new(*x, *y);
-                    let global = local_to_global(&local, &chunk_index);
-
-                    if terrain
-                        .get_latest_simulation(&global)
-                        .map_or(true, |frame| frame == simulation_frame)
-                    {
-                        continue;
-                    };
-
-                    simulate_texel(global, &mut terrain, &frame_counter);
-                }
-            }
+#[derive(Resource, Default)]
+pub struct TerrainSimulationSettings2D {
+    /// Stop simulating texels, e.g. with the `sim pause` console command
+    pub paused: bool,
+    /// Stop simulating texels while the level editor is open. Kept apart from `paused`, so that closing the editor
+    /// doesn't resume a simulation that was paused before.
+    pub paused_by_editor: bool,
+    /// Frames to simulate while paused, one per frame
+    pub step_frames: u32,
+}
 
-            // Gas dispersion
-            let alternate_dispersion = frame_counter.frame % 2 == 0;
-            let alternate = if alternate_dispersion { 1 } else { 0 };
-            let y_range =
-                ((rect.min.y - alternate)..rect.max.y + 1 + alternate).collect::<Vec<_>>();
-            let x_range =
-                ((rect.min.x - alternate)..rect.max.x + 1 + alternate).collect::<Vec<_>>();
-            const DISPERSION_WIDTH: usize = 2;
-            const DISPERSION_HEIGHT: usize = 2;
-            for y_arr in y_range.chunks(DISPERSION_HEIGHT) {
-                for x_arr in x_range.chunks(DISPERSION_WIDTH) {
-                    let mut global_positions = vec![];
-                    for y in y_arr.iter() {
-                        for x in x_arr.iter() {
-                            let local = Vector2I::new(*x, *y);
-                            let global = local_to_global(&local, &chunk_index);
-                            global_positions.push(global);
-                        }
-                    }
-
-                    // Distribute gas
-                    disperse_gas(global_positions, &mut terrain, &frame_counter)
-                }
-            }
-        }
+impl TerrainSimulationSettings2D {
+    pub fn is_paused(&self) -> bool {
+        self.paused || self.paused_by_editor
     }
 }
 
-fn disperse_gas(
-    global_positions: Vec<Vector2I>,
-    terrain: &mut Terrain2D,
-    frame_counter: &FrameCounter,
+/// Run `Terrain2D::step` once per frame, unless the simulation is paused
+fn terrain_simulation(
+    mut terrain: ResMut<Terrain2D>,
+    frame_counter: Res<FrameCounter>,
+    mut settings: ResMut<TerrainSimulationSettings2D>,
+    mut random: ResMut<SimulationRandom2D>,
+    mut metrics: ResMut<TerrainMetrics>,
+    // Dirty rects collected while paused, so that edited texels get simulated once the simulation continues
+    mut paused_dirty_rects: Local<HashMap<Chunk2DIndex, ChunkRect>>,
 ) {
-    use u32 as Capacity;
-    let mut total_densities: HashMap<TexelID, Capacity> = HashMap::new();
-    // let mut total_densities: Vec<(TexelID, Capacity)> = vec![];
-    let mut valid_globals = vec![];
-    for global in global_positions.iter() {
-        let (texel, behaviour) = terrain.get_texel_behaviour(global);
-        if behaviour.clone().map_or(true, |b| b.form == TexelForm::Gas) {
-            valid_globals.push(*global);
-        }
-        match (texel, behaviour) {
-            (Some(texel), Some(behaviour)) => {
-                if behaviour.form == TexelForm::Gas {
-                    total_densities.insert(
-                        texel.id,
-                        texel.density as u32
-                            + total_densities.get(&texel.id).map_or(0, |density| *density),
-                    );
-                }
+    if settings.is_paused() && settings.step_frames > 0 {
+        settings.step_frames -= 1;
+    } else if settings.is_paused() {
+        for (index, chunk) in terrain.chunk_iter_mut() {
+            if let Some(rect) = chunk.dirty_rect.take() {
+                paused_dirty_rects
+                    .entry(*index)
+                    .and_modify(|paused| {
+                        *paused = paused.include_point(rect.min).include_point(rect.max)
+                    })
+                    .or_insert(rect);
             }
-            (_, _) => (),
         }
-    }
-
-    let mut total_densities: Vec<(TexelID, Capacity)> =
-        total_densities.iter().map(|(t, d)| (*t, *d)).collect();
-
-    if total_densities.len() == 0 {
         return;
     }
-
-    total_densities.sort_unstable_by_key(|(_, density)| *density);
-    total_densities.reverse();
-
-    const TILE_CAPACITY: u32 = u8::MAX as u32;
-    let free_slots = valid_globals.len() as u32
-        - total_densities
-            .iter()
-            .map(|(_, v)| (*v / (TILE_CAPACITY + 1)) + 1)
-            .sum::<u32>();
-
-    // Allocate slots
-    let mut slots: Vec<(TexelID, u32)> = vec![];
-    for (id, density) in total_densities.iter() {
-        let min_slots = (density / (TILE_CAPACITY + 1)) + 1;
-        slots.push((*id, min_slots));
-    }
-    for i in 0..free_slots as usize {
-        let len = slots.len();
-        slots[i % len].1 += 1;
-    }
-
-    // Disperse into given slots
-    let mut texels: Vec<Texel2D> = vec![];
-    for (id, total_density) in total_densities.iter() {
-        let slots = slots.iter().find(|s| s.0 == *id).unwrap().1;
-        let mut density_left = *total_density;
-        for i in 0..slots {
-            let density = if i < (slots - 1) {
-                (total_density / slots).min(density_left)
-            } else {
-                density_left
-            }
-            .min(u8::MAX as u32);
-            if density > 0 {
-                texels.push(Texel2D {
-                    id: *id,
-                    density: density as u8,
-                });
-                density_left -= density;
-            }
-        }
-    }
-
-    // Apply changes
-    if texels.len() > valid_globals.len() {
-        panic!("disperse_gas() - valid_globals is shorter than texels");
-    }
-
-    for i in 0..valid_globals.len() {
-        let global = valid_globals[i];
-        if i < texels.len() {
-            let texel = texels[i];
-            terrain.set_texel(&global, texel, None);
-        } else {
-            terrain.set_texel(&global, Texel2D::default(), None)
+    for (index, rect) in paused_dirty_rects.drain() {
+        if let Some(chunk) = terrain.index_to_chunk_mut(&index) {
+            chunk.mark_dirty_rect(&rect);
         }
     }
-}
-
-fn simulate_texel(global: Vector2I, terrain: &mut Terrain2D, frame_counter: &FrameCounter) {
-    let (_, behaviour) = match terrain.get_texel_behaviour(&global) {
-        (Some(texel), Some(behaviour)) => (texel, behaviour),
-        (_, _) => return,
-    };
-
-    let simulation_frame = (frame_counter.frame % u8::MAX as u64) as u8 + 1;
-
-    // Gravity
-    if let Some(gravity) = behaviour.gravity {
-        let grav_offset = Vector2I::from(gravity);
-        let grav_pos = global + grav_offset;
-
-        // Try falling
-        {
-            let (_, other_behaviour) = terrain.get_texel_behaviour(&grav_pos);
-            if TexelBehaviour2D::can_displace(&behaviour, &other_behaviour) {
-                terrain.swap_texels(&global, &grav_pos, Some(simulation_frame));
-                return;
-            }
-            if terrain.can_transfer_density(&global, &grav_pos) {
-                terrain.transfer_density(&global, &grav_pos, gravity, Some(simulation_frame))
-            }
-        }
 
-        // Try "sliding"
-        let mut dirs = vec![Vector2I::RIGHT, Vector2I::LEFT];
-        if ((frame_counter.frame / 73) % 2) as i32 == global.y % 2 {
-            dirs.reverse();
-        }
-        for dir in dirs.iter() {
-            let slide_pos = match behaviour.form {
-                TexelForm::Solid => grav_pos + *dir,
-                TexelForm::Liquid | TexelForm::Gas => global + *dir,
-            };
-            let (_, other_behaviour) = terrain.get_texel_behaviour(&slide_pos);
-            if TexelBehaviour2D::can_displace(&behaviour, &other_behaviour) {
-                terrain.swap_texels(&global, &slide_pos, Some(simulation_frame));
-                return;
-            }
-            if terrain.can_transfer_density(&global, &grav_pos) {
-                terrain.transfer_density(&global, &grav_pos, gravity, Some(simulation_frame))
-            }
-        }
-    }
+    let start = Instant::now();
+    let stats = terrain.step(frame_counter.frame, &mut random.random);
+    metrics.current.active_chunks += stats.active_chunks;
+    metrics.current.simulated_texels += stats.simulated_texels;
+    metrics.current.gas_dispersion_ms += stats.gas_dispersion_ms;
+    metrics.current.simulation_ms += elapsed_ms(start) - stats.gas_dispersion_ms;
 }
 
 fn emit_terrain_events(
     mut terrain: ResMut<Terrain2D>,
+    frame_counter: Res<FrameCounter>,
     mut terrain_events: EventWriter<TerrainEvent2D>,
+    mut delta_events: EventWriter<TerrainDelta2D>,
 ) {
+    if let Some(delta) = terrain.take_delta(frame_counter.frame) {
+        delta_events.send(delta);
+    }
     for event in terrain.events.drain(..) {
         terrain_events.send(event);
     }
@@ -317,13 +184,24 @@ fn emit_terrain_events(
 pub enum TerrainEvent2D {
     ChunkAdded(Chunk2DIndex),
     ChunkRemoved(Chunk2DIndex),
+    /// Dirty rect of a chunk. The texels that changed are recorded by the delta stream, see `TerrainDelta2D`.
     TexelsUpdated(Chunk2DIndex, ChunkRect),
 }
 
+/// Texel replaced through `Terrain2D::set_texels`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TexelChange2D {
+    pub global: Vector2I,
+    pub before: Texel2D,
+    pub after: Texel2D,
+}
+
 #[derive(Default, Resource)]
 pub struct Terrain2D {
     chunk_map: HashMap<Chunk2DIndex, Chunk2D>,
     events: Vec<TerrainEvent2D>,
+    delta_stream: Option<TerrainDeltaStream2D>,
+    change_cause: TexelChangeCause2D,
     pub top_boundary: Option<i32>,
     pub bottom_boundary: Option<i32>,
     pub left_boundary: Option<i32>,
@@ -340,6 +218,8 @@ impl Terrain2D {
         Terrain2D {
             chunk_map: HashMap::new(),
             events: Vec::new(),
+            delta_stream: None,
+            change_cause: TexelChangeCause2D::default(),
             top_boundary,
             bottom_boundary,
             left_boundary,
@@ -402,6 +282,27 @@ impl Terrain2D {
         }
     }
 
+    /// Mark the texels of a global rectangle dirty, once per chunk
+    pub fn mark_dirty_rect(&mut self, rect: &Rect2I) {
+        let chunks = Rect2I::new(
+            global_to_chunk_index(&rect.min),
+            global_to_chunk_index(&rect.max),
+        );
+        for index in chunks.points() {
+            let origin = chunk_index_to_global(&index);
+            let chunk_rect = Rect2I::new(origin, origin + Chunk2D::SIZE - Vector2I::ONE);
+            if let (Some(chunk), Some(dirty)) = (
+                self.chunk_map.get_mut(&index),
+                rect.intersection(&chunk_rect),
+            ) {
+                chunk.mark_dirty_rect(&ChunkRect {
+                    min: dirty.min - origin,
+                    max: dirty.max - origin,
+                });
+            }
+        }
+    }
+
     pub fn is_within_boundaries(&self, global: &Vector2I) -> bool {
         if let Some(top) = self.top_boundary {
             if global.y >= top {
@@ -437,6 +338,12 @@ impl Terrain2D {
         })
     }
 
+    pub fn clear_latest_simulation(&mut self, global: &Vector2I) {
+        if let Some(chunk) = self.global_to_chunk_mut(global) {
+            chunk.clear_latest_simulation(&global_to_local(global));
+        }
+    }
+
     pub fn get_texel_behaviour(
         &self,
         global: &Vector2I,
@@ -452,6 +359,34 @@ impl Terrain2D {
         )
     }
 
+    /// Collision of the texels surrounding the chunk. Texels outside of the boundaries count as solid.
+    pub fn chunk_border(&self, index: &Chunk2DIndex, motion: &ChunkMotion2D) -> ChunkBorder2D {
+        let origin = chunk_index_to_global(index);
+        let outside = |local: Vector2I| {
+            let global = origin + local;
+            if !self.is_within_boundaries(&global) {
+                return BorderTexel2D::Collider(TexelBehaviour2D::OUT_OF_BOUNDS.collider());
+            }
+            match self.global_to_chunk(&global) {
+                Some(chunk) => local_to_texel_index(&global_to_local(&global))
+                    .and_then(|i| chunk.collider_properties(i, motion))
+                    .map_or(BorderTexel2D::Empty, BorderTexel2D::Collider),
+                None => BorderTexel2D::MissingChunk,
+            }
+        };
+
+        let mut border = ChunkBorder2D::default();
+        for x in 0..Chunk2D::SIZE_X {
+            border.up[x] = outside(Vector2I::new(x as i32, Chunk2D::SIZE.y));
+            border.down[x] = outside(Vector2I::new(x as i32, -1));
+        }
+        for y in 0..Chunk2D::SIZE_Y {
+            border.right[y] = outside(Vector2I::new(Chunk2D::SIZE.x, y as i32));
+            border.left[y] = outside(Vector2I::new(-1, y as i32));
+        }
+        border
+    }
+
     pub fn set_texel(
         &mut self,
         global: &Vector2I,
@@ -461,6 +396,12 @@ impl Terrain2D {
         if !self.is_within_boundaries(global) {
             return;
         }
+        let before = self.get_texel(global);
+        if before.map_or(false, |texel| {
+            texel.id != new_texel.id && TexelBehaviour2D::is_indestructible(&texel.id)
+        }) {
+            return;
+        }
         let index = global_to_chunk_index(global);
         let changed = match self.index_to_chunk_mut(&index) {
             Some(chunk) => chunk.set_texel(&global_to_local(global), new_texel, simulation_frame),
@@ -473,6 +414,7 @@ impl Terrain2D {
             }
         };
         if changed {
+            self.record_delta(*global, before.unwrap_or_default(), new_texel);
             self.mark_dirty(&(*global + Vector2I::UP));
             self.mark_dirty(&(*global + Vector2I::RIGHT));
             self.mark_dirty(&(*global + Vector2I::DOWN));
@@ -480,6 +422,54 @@ impl Terrain2D {
         }
     }
 
+    /// Set many texels at once, following the same rules as `set_texel`. Each affected chunk is marked dirty only
+    /// once, instead of once per texel. Returns the texels that changed, in the order they were set.
+    pub fn set_texels(
+        &mut self,
+        texels: impl IntoIterator<Item = (Vector2I, Texel2D)>,
+    ) -> Vec<TexelChange2D> {
+        let mut changes: Vec<TexelChange2D> = vec![];
+        let mut dirty_rects: HashMap<Chunk2DIndex, Rect2I> = HashMap::new();
+        for (global, new_texel) in texels {
+            if !self.is_within_boundaries(&global) {
+                continue;
+            }
+            let before = self.get_texel(&global);
+            if before.map_or(false, |texel| {
+                texel.id != new_texel.id && TexelBehaviour2D::is_indestructible(&texel.id)
+            }) {
+                continue;
+            }
+            let index = global_to_chunk_index(&global);
+            if self.index_to_chunk(&index).is_none() {
+                self.add_chunk(index, Chunk2D::new());
+            }
+            let changed = self.index_to_chunk_mut(&index).map_or(false, |chunk| {
+                chunk.replace_texel(&global_to_local(&global), new_texel, None)
+            });
+            if changed {
+                self.record_delta(global, before.unwrap_or_default(), new_texel);
+                changes.push(TexelChange2D {
+                    global,
+                    before: before.unwrap_or_default(),
+                    after: new_texel,
+                });
+                dirty_rects
+                    .entry(index)
+                    .and_modify(|rect| *rect = rect.include_point(global))
+                    .or_insert(Rect2I::new(global, global));
+            }
+        }
+        // Neighbours of the changed texels are woken up too, like in `set_texel`
+        for rect in dirty_rects.values() {
+            self.mark_dirty_rect(&Rect2I::new(
+                rect.min - Vector2I::ONE,
+                rect.max + Vector2I::ONE,
+            ));
+        }
+        changes
+    }
+
     pub fn swap_texels(
         &mut self,
         from_global: &Vector2I,
@@ -557,6 +547,11 @@ pub fn local_to_texel_index(position: &Vector2I) -> Option<usize> {
     }
 }
 
+/// Number of simulation frames between the frames, taking the wrap around into account
+pub fn simulation_frames_since(current: u8, previous: u8) -> u8 {
+    ((current as u16 + u8::MAX as u16 - previous as u16) % u8::MAX as u16) as u8
+}
+
 pub fn texel_index_to_local(i: usize) -> Vector2I {
     Vector2I {
         x: i as i32 % Chunk2D::SIZE.x,
@@ -0,0 +1,332 @@
+use std::collections::VecDeque;
+
+use noise::{NoiseFn, PerlinSurflet};
+
+use crate::util::{Random, Rect2I, Vector2I};
+
+/// Share of open space starting from the given depth. Depth is measured downwards from the top of the generated region.
+#[derive(Clone, Copy, Debug)]
+pub struct CaveBand2D {
+    pub depth: i32,
+    /// Share of open texels in range [0, 1]
+    pub open_share: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct CaveSettings2D {
+    /// Width of the carved tunnels in texels
+    pub tunnel_width: i32,
+    /// Frequency of the initial cave noise. Higher values create smaller and more frequent caves.
+    pub frequency: f64,
+    /// Open space by depth. A band applies until the depth of the next band, so they should be sorted by depth.
+    pub bands: Vec<CaveBand2D>,
+    /// Number of cellular automata passes. More passes create smoother caves.
+    pub smoothing_steps: u32,
+    /// Depth that the main cave network is guaranteed to reach from the surface
+    pub target_depth: i32,
+    /// Caves smaller than this (in texels) are filled instead of connected to the main network
+    pub min_cave_size: usize,
+}
+
+impl Default for CaveSettings2D {
+    fn default() -> Self {
+        CaveSettings2D {
+            tunnel_width: 5,
+            frequency: 1.0 / 24.0,
+            bands: vec![
+                CaveBand2D {
+                    depth: 0,
+                    open_share: 0.3,
+                },
+                CaveBand2D {
+                    depth: 96,
+                    open_share: 0.4,
+                },
+                CaveBand2D {
+                    depth: 256,
+                    open_share: 0.45,
+                },
+            ],
+            smoothing_steps: 4,
+            target_depth: 400,
+            min_cave_size: 48,
+        }
+    }
+}
+
+impl CaveSettings2D {
+    pub fn open_share_at(&self, depth: i32) -> f64 {
+        self.bands
+            .iter()
+            .take_while(|band| band.depth <= depth)
+            .last()
+            .or(self.bands.first())
+            .map_or(0.0, |band| band.open_share.clamp(0.0, 1.0))
+    }
+}
+
+/// Open/solid map of a generated cave system
+pub struct CaveMap2D {
+    pub region: Rect2I,
+    /// Top of the main tunnel. Every kept cave is reachable from it.
+    pub entrance: Vector2I,
+    open: Vec<bool>,
+}
+
+impl CaveMap2D {
+    /// Is the global position carved out. Positions outside of the region are never open.
+    pub fn is_open(&self, global: &Vector2I) -> bool {
+        self.region
+            .index_of(global)
+            .map_or(false, |index| self.open[index])
+    }
+}
+
+/// Cave generator that combines noise, cellular automata smoothing and tunnel carving.
+///
+/// Every cave that is kept is connected to the main network, which reaches from the top of the region to the target depth.
+pub struct CaveGen2D {
+    pub seed: u32,
+    pub settings: CaveSettings2D,
+    noise: PerlinSurflet,
+}
+
+impl CaveGen2D {
+    pub fn new(seed: u32, settings: CaveSettings2D) -> CaveGen2D {
+        CaveGen2D {
+            seed,
+            settings,
+            noise: PerlinSurflet::new(seed.wrapping_add(1)),
+        }
+    }
+
+    pub fn generate(&self, region: Rect2I) -> CaveMap2D {
+        let mut random = Random::new(self.seed as u64);
+        let mut open = self.initial_fill(&region, &mut random);
+        for _ in 0..self.settings.smoothing_steps {
+            open = smooth(&region, &open);
+        }
+
+        let entrance = self.carve_main_tunnel(&region, &mut open, &mut random);
+        fill_small_caves(&region, &mut open, self.settings.min_cave_size, &entrance);
+        self.connect_caves(&region, &mut open, &entrance);
+
+        CaveMap2D {
+            region,
+            entrance,
+            open,
+        }
+    }
+
+    /// Threshold noise so that each depth band gets its share of open space
+    fn initial_fill(&self, region: &Rect2I, random: &mut Random) -> Vec<bool> {
+        let frequency = self.settings.frequency;
+        let values: Vec<f64> = region
+            .points()
+            .map(|point| {
+                self.noise
+                    .get([point.x as f64 * frequency, point.y as f64 * frequency])
+                    + (random.next_f64() - 0.5) * 0.25
+            })
+            .collect();
+
+        let mut open = vec![false; region.area()];
+        let mut band_start = 0;
+        while band_start < region.height() {
+            let share = self.settings.open_share_at(band_start);
+            let band_end = self
+                .settings
+                .bands
+                .iter()
+                .find(|band| band.depth > band_start)
+                .map_or(region.height(), |band| band.depth.min(region.height()));
+
+            // Rows are stored from the bottom up, depth goes from the top down
+            let first = ((region.height() - band_end) * region.width()) as usize;
+            let last = ((region.height() - band_start) * region.width()) as usize;
+            let mut sorted: Vec<f64> = values[first..last].to_vec();
+            sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+            let open_count = (sorted.len() as f64 * share) as usize;
+            if open_count > 0 {
+                let threshold = sorted[open_count - 1];
+                for (open, value) in open[first..last].iter_mut().zip(values[first..last].iter()) {
+                    *open = *value <= threshold;
+                }
+            }
+
+            band_start = band_end;
+        }
+        open
+    }
+
+    /// Carve a winding tunnel from the top of the region down to the target depth
+    fn carve_main_tunnel(
+        &self,
+        region: &Rect2I,
+        open: &mut [bool],
+        random: &mut Random,
+    ) -> Vector2I {
+        let margin = self.settings.tunnel_width + 1;
+        let entrance = Vector2I {
+            x: random.range_i32(
+                region.min.x + region.width() / 4,
+                region.max.x - region.width() / 4 + 1,
+            ),
+            y: region.max.y,
+        };
+        let bottom = (region.max.y - self.settings.target_depth).max(region.min.y + margin);
+
+        let mut position = entrance;
+        let mut drift = 0;
+        while position.y > bottom {
+            carve_circle(region, open, &position, self.settings.tunnel_width);
+            if random.chance(0.2) {
+                drift = random.range_i32(-1, 2);
+            }
+            position.x = (position.x + drift).clamp(region.min.x + margin, region.max.x - margin);
+            position.y -= 1;
+        }
+        carve_circle(region, open, &position, self.settings.tunnel_width);
+        entrance
+    }
+
+    /// Connect every remaining cave to the main network.
+    ///
+    /// A breadth-first search grows outwards from the main network through solid texels.
+    /// When it reaches another cave, the path back to the network is carved and the cave joins the search.
+    fn connect_caves(&self, region: &Rect2I, open: &mut [bool], entrance: &Vector2I) {
+        let mut parents: Vec<Option<usize>> = vec![None; open.len()];
+        let mut visited = vec![false; open.len()];
+        let mut queue: VecDeque<usize> = VecDeque::new();
+
+        let start = region.index_of(entrance).unwrap();
+        queue.extend(flood(region, open, start, &mut visited));
+
+        while let Some(index) = queue.pop_front() {
+            let point = region.point_at(index);
+            for offset in NEIGHBOURS {
+                let neighbour = match region.index_of(&(point + offset)) {
+                    Some(neighbour) if !visited[neighbour] => neighbour,
+                    _ => continue,
+                };
+                parents[neighbour] = Some(index);
+
+                if !open[neighbour] {
+                    visited[neighbour] = true;
+                    queue.push_back(neighbour);
+                    continue;
+                }
+
+                // Found a new cave. Collect the path before carving, since carving opens up the nearby texels.
+                let mut path = vec![];
+                let mut current = parents[neighbour];
+                while let Some(path_index) = current {
+                    if open[path_index] {
+                        break;
+                    }
+                    path.push(path_index);
+                    current = parents[path_index];
+                }
+                for path_index in path {
+                    carve_circle(
+                        region,
+                        open,
+                        &region.point_at(path_index),
+                        self.settings.tunnel_width,
+                    );
+                }
+                queue.extend(flood(region, open, neighbour, &mut visited));
+            }
+        }
+    }
+}
+
+const NEIGHBOURS: [Vector2I; 4] = [
+    Vector2I::UP,
+    Vector2I::RIGHT,
+    Vector2I::DOWN,
+    Vector2I::LEFT,
+];
+
+/// Cellular automata pass. Texels surrounded by mostly solid texels become solid and vice versa.
+fn smooth(region: &Rect2I, open: &[bool]) -> Vec<bool> {
+    let mut result = open.to_vec();
+    for (index, point) in region.points().enumerate() {
+        let mut solid_neighbours = 0;
+        for y in -1..=1 {
+            for x in -1..=1 {
+                if x == 0 && y == 0 {
+                    continue;
+                }
+                // Outside of the region counts as solid
+                let is_open = region
+                    .index_of(&(point + Vector2I { x, y }))
+                    .map_or(false, |i| open[i]);
+                if !is_open {
+                    solid_neighbours += 1;
+                }
+            }
+        }
+        if solid_neighbours > 4 {
+            result[index] = false;
+        } else if solid_neighbours < 4 {
+            result[index] = true;
+        }
+    }
+    result
+}
+
+/// Indices of the unvisited open texels connected to the starting index. The found texels are marked as visited.
+fn flood(region: &Rect2I, open: &[bool], start: usize, visited: &mut [bool]) -> Vec<usize> {
+    let mut result = vec![];
+    if !open[start] || visited[start] {
+        return result;
+    }
+    let mut queue = VecDeque::from([start]);
+    visited[start] = true;
+    while let Some(index) = queue.pop_front() {
+        result.push(index);
+        let point = region.point_at(index);
+        for offset in NEIGHBOURS {
+            if let Some(neighbour) = region.index_of(&(point + offset)) {
+                if open[neighbour] && !visited[neighbour] {
+                    visited[neighbour] = true;
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Fill caves that are too small to be worth connecting. The cave containing `keep` is never filled.
+fn fill_small_caves(region: &Rect2I, open: &mut [bool], min_size: usize, keep: &Vector2I) {
+    let keep = region.index_of(keep);
+    let mut visited = vec![false; open.len()];
+    for start in 0..open.len() {
+        let cave = flood(region, open, start, &mut visited);
+        if cave.is_empty() || cave.len() >= min_size {
+            continue;
+        }
+        if keep.map_or(false, |keep| cave.contains(&keep)) {
+            continue;
+        }
+        for index in cave {
+            open[index] = false;
+        }
+    }
+}
+
+fn carve_circle(region: &Rect2I, open: &mut [bool], center: &Vector2I, width: i32) {
+    let radius = (width / 2).max(0);
+    for y in -radius..=radius {
+        for x in -radius..=radius {
+            if x * x + y * y > radius * radius {
+                continue;
+            }
+            if let Some(index) = region.index_of(&(*center + Vector2I { x, y })) {
+                open[index] = true;
+            }
+        }
+    }
+}
@@ -1,11 +1,23 @@
-use std::collections::VecDeque;
+use std::{
+    cmp::Reverse,
+    collections::{HashSet, VecDeque},
+    time::Instant,
+};
 
 use super::*;
-use crate::util::{CollisionLayers, Segment2I, Vector2I};
-use bevy::render::{render_resource::Extent3d, texture::ImageSampler};
+use crate::util::{
+    frame_counter::FrameCounter,
+    polyline::{simplify_polylines, Simplification},
+    CollisionLayers, OneWayCollider, Rect2I, Segment2I, Vector2I,
+};
+use bevy::{
+    render::{render_resource::Extent3d, texture::ImageSampler},
+    tasks::{AsyncComputeTaskPool, Task},
+};
+use futures_lite::future;
 use lazy_static::lazy_static;
 
-type Island = VecDeque<Segment2I>;
+pub type Island = VecDeque<Segment2I>;
 pub type Chunk2DIndex = Vector2I;
 pub type NeighbourMask = u8;
 
@@ -37,7 +49,8 @@ lazy_static! {
         /* 0b1111 */ vec![],
     ];
 
-    /// Version of the MS case dictionary that is used by the solid tiles at the edge of the chunk
+    /// Version of the MS case dictionary that is used by the solid tiles at the edge of the chunk, when there is no
+    /// neighbouring chunk to continue the outline
     static ref MST_EDGE_CASE_MAP: [Segment2I; 4] = [
         /* up    */ Segment2I { from: Vector2I::UP, to: Vector2I::ONE },
         /* right */ Segment2I { from: Vector2I::ONE, to: Vector2I::RIGHT },
@@ -82,7 +95,133 @@ pub struct ChunkColliderBundle {
     pub transform: TransformBundle,
 }
 
-#[derive(Clone, Copy)]
+/// Settings for generating the chunk colliders
+#[derive(Resource, Clone)]
+pub struct ChunkColliderSettings2D {
+    /// Simplify the collider outlines. Without simplification, sloped surfaces are made of small steps.
+    pub simplification: Option<Simplification>,
+    /// Maximum distance in texels between the simplified outline and the texels
+    pub tolerance: f32,
+    /// Texels with gravity have no collision until they have stayed in place for this many simulation frames.
+    /// Should be less than `u8::MAX / 2`, since the simulation frames wrap around.
+    pub settle_frames: u8,
+    /// Build the colliders during the frame instead of in background tasks.
+    /// Slower, but the colliders don't depend on when the tasks finish, which keeps replays deterministic.
+    pub blocking: bool,
+}
+
+impl Default for ChunkColliderSettings2D {
+    fn default() -> Self {
+        ChunkColliderSettings2D {
+            simplification: Some(Simplification::DouglasPeucker),
+            tolerance: 1.0,
+            settle_frames: 8,
+            blocking: false,
+        }
+    }
+}
+
+impl ChunkColliderSettings2D {
+    pub fn simplify(&self, outlines: Vec<ColliderOutline2D>) -> Vec<ColliderOutline2D> {
+        let method = match self.simplification {
+            Some(method) => method,
+            None => return outlines,
+        };
+        let (properties, polylines): (Vec<_>, Vec<_>) = outlines
+            .into_iter()
+            .map(|outline| (outline.properties, outline.polyline))
+            .unzip();
+        // Simplified together, so that the outlines of different materials don't cross each other
+        simplify_polylines(&polylines, method, self.tolerance)
+            .into_iter()
+            .zip(properties)
+            .map(|(polyline, properties)| ColliderOutline2D {
+                properties,
+                polyline,
+            })
+            .collect()
+    }
+}
+
+/// Collider outline with the properties of the material it surrounds
+#[derive(Clone, Debug)]
+pub struct ColliderOutline2D {
+    pub properties: TexelColliderProperties2D,
+    pub polyline: Vec<Vec2>,
+}
+
+/// Texel just outside of a chunk
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BorderTexel2D {
+    /// There is no neighbouring chunk. Solid texels next to it close their islands with an edge segment.
+    #[default]
+    MissingChunk,
+    Empty,
+    Collider(TexelColliderProperties2D),
+}
+
+/// Collision of the texels just outside of a chunk, so that the chunk outlines continue into the neighbouring chunks
+#[derive(Clone, Debug)]
+pub struct ChunkBorder2D {
+    /// Indexed by local x
+    pub up: [BorderTexel2D; Chunk2D::SIZE_X],
+    /// Indexed by local y
+    pub right: [BorderTexel2D; Chunk2D::SIZE_Y],
+    /// Indexed by local x
+    pub down: [BorderTexel2D; Chunk2D::SIZE_X],
+    /// Indexed by local y
+    pub left: [BorderTexel2D; Chunk2D::SIZE_Y],
+}
+
+impl Default for ChunkBorder2D {
+    fn default() -> Self {
+        ChunkBorder2D {
+            up: [BorderTexel2D::MissingChunk; Chunk2D::SIZE_X],
+            right: [BorderTexel2D::MissingChunk; Chunk2D::SIZE_Y],
+            down: [BorderTexel2D::MissingChunk; Chunk2D::SIZE_X],
+            left: [BorderTexel2D::MissingChunk; Chunk2D::SIZE_Y],
+        }
+    }
+}
+
+impl ChunkBorder2D {
+    /// Neighbours of a local position that are outside of the chunk, in the order of NEIGHBOUR_OFFSET_VECTORS.
+    /// None means that the neighbour is inside the chunk.
+    pub fn outside_neighbours(&self, local: &Vector2I) -> [Option<BorderTexel2D>; 4] {
+        let (x, y) = (local.x as usize, local.y as usize);
+        [
+            (local.y == Chunk2D::SIZE.y - 1).then(|| self.up[x]),
+            (local.x == Chunk2D::SIZE.x - 1).then(|| self.right[y]),
+            (local.y == 0).then(|| self.down[x]),
+            (local.x == 0).then(|| self.left[y]),
+        ]
+    }
+}
+
+/// Decides which texels are still moving and are left out of the colliders
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkMotion2D {
+    pub simulation_frame: u8,
+    pub settle_frames: u8,
+}
+
+impl ChunkMotion2D {
+    /// Nothing is considered to be moving
+    pub const NONE: ChunkMotion2D = ChunkMotion2D {
+        simulation_frame: 0,
+        settle_frames: 0,
+    };
+
+    /// Did a texel that was last moved on the given simulation frame move recently.
+    /// Zero means that the texel has not moved since it was placed or since it came to rest.
+    pub fn is_moving(&self, latest_simulation: u8) -> bool {
+        latest_simulation != 0
+            && simulation_frames_since(self.simulation_frame, latest_simulation)
+                < self.settle_frames
+    }
+}
+
+#[derive(Clone, Copy, Hash)]
 pub struct ChunkRect {
     pub min: Vector2I,
     pub max: Vector2I,
@@ -162,6 +301,11 @@ impl Chunk2D {
         }
     }
 
+    pub fn mark_dirty_rect(&mut self, rect: &ChunkRect) {
+        self.mark_dirty(&rect.min);
+        self.mark_dirty(&rect.max);
+    }
+
     pub fn mark_clean(&mut self) {
         self.dirty_rect = None;
     }
@@ -174,6 +318,32 @@ impl Chunk2D {
         local_to_texel_index(position).map(|i| self.simulation_frames[i])
     }
 
+    /// Forget the frame on which the texel was last simulated, see `ChunkMotion2D::is_moving`
+    pub fn clear_latest_simulation(&mut self, position: &Vector2I) {
+        if let Some(i) = local_to_texel_index(position) {
+            self.simulation_frames[i] = 0;
+        }
+    }
+
+    /// Does the texel take part in the collision. Texels with gravity that are still moving don't.
+    pub fn is_collider(&self, i: usize, motion: &ChunkMotion2D) -> bool {
+        let id = &self.texels[i].id;
+        TexelBehaviour2D::has_collision(id)
+            && !(TexelBehaviour2D::has_gravity(id) && motion.is_moving(self.simulation_frames[i]))
+    }
+
+    /// Collider properties of the texel, if it takes part in the collision
+    pub fn collider_properties(
+        &self,
+        i: usize,
+        motion: &ChunkMotion2D,
+    ) -> Option<TexelColliderProperties2D> {
+        if !self.is_collider(i, motion) {
+            return None;
+        }
+        TexelBehaviour2D::collider_properties(&self.texels[i].id)
+    }
+
     pub fn get_texel_mut(&mut self, position: &Vector2I) -> Option<&mut Texel2D> {
         local_to_texel_index(position).map(|i| &mut self.texels[i])
     }
@@ -183,12 +353,25 @@ impl Chunk2D {
         position: &Vector2I,
         new_texel: Texel2D,
         simulation_frame: Option<u8>,
+    ) -> bool {
+        let changed = self.replace_texel(position, new_texel, simulation_frame);
+        if changed {
+            self.mark_dirty(position);
+        }
+        changed
+    }
+
+    /// Same as `set_texel`, but leaves marking the texel dirty to the caller
+    pub fn replace_texel(
+        &mut self,
+        position: &Vector2I,
+        new_texel: Texel2D,
+        simulation_frame: Option<u8>,
     ) -> bool {
         let i = local_to_texel_index(position).expect("Texel index out of range");
         if self.texels[i] == new_texel {
             return false;
         }
-        self.mark_dirty(position);
         let update_neighbours = self.texels[i].has_collision() != new_texel.has_collision();
         self.texels[i] = new_texel;
         // Update simulation frame
@@ -232,137 +415,268 @@ impl Chunk2D {
         image_data
     }
 
-    // TODO: Don't create collision for falling texels, it's pretty annoying that a stream of small grains blocks movement
-    pub fn create_collision_data(&self) -> Vec<Vec<Vec2>> {
-        let mut islands: Vec<Island> = Vec::new();
+    /// Collider outlines of the chunk. Outlines that continue into a neighbouring chunk are left open at the border.
+    ///
+    /// Moving texels are left out, so that a stream of falling grains doesn't block movement.
+    pub fn create_collision_data(
+        &self,
+        border: &ChunkBorder2D,
+        motion: &ChunkMotion2D,
+    ) -> Vec<ColliderOutline2D> {
+        self.collision_snapshot(border, motion)
+            .create_collision_data()
+    }
+
+    /// Are there texels that would have collision once they stop moving
+    pub fn has_moving_texels(&self, motion: &ChunkMotion2D) -> bool {
+        (0..self.texels.len())
+            .any(|i| self.texels[i].has_collision() && !self.is_collider(i, motion))
+    }
+
+    /// Copy the state needed for building the colliders
+    pub fn collision_snapshot(
+        &self,
+        border: &ChunkBorder2D,
+        motion: &ChunkMotion2D,
+    ) -> ChunkCollisionSnapshot2D {
+        let mut snapshot = ChunkCollisionSnapshot2D {
+            colliders: [None; Self::SIZE_X * Self::SIZE_Y],
+            neighbour_mask: self.neighbour_mask,
+            border: border.clone(),
+        };
         for i in 0..self.texels.len() {
-            let local = texel_index_to_local(i);
+            snapshot.colliders[i] = self.collider_properties(i, motion);
+        }
 
-            let edge_mask: u8 = if local.y == Chunk2D::SIZE.y - 1 {
-                1 << 0
-            } else {
-                0
-            } | if local.x == Chunk2D::SIZE.x - 1 {
-                1 << 1
-            } else {
-                0
-            } | if local.y == 0 { 1 << 2 } else { 0 }
-                | if local.x == 0 { 1 << 3 } else { 0 };
-
-            let mut sides: Vec<Segment2I>;
-            let has_collision = TexelBehaviour2D::has_collision(&self.texels[i].id);
-            if !has_collision {
-                sides = MST_CASE_MAP[self.neighbour_mask[i] as usize]
-                    .iter()
-                    .clone()
-                    .map(|side| Segment2I {
-                        from: side.from + local,
-                        to: side.to + local,
-                    })
-                    .collect();
-            } else if has_collision && edge_mask != 0 {
-                sides = Vec::with_capacity(Chunk2D::SIZE_X * 2 + Chunk2D::SIZE_Y * 2);
-                for i in 0..MST_EDGE_CASE_MAP.len() {
-                    if edge_mask & (1 << i) != 0 {
-                        let edge = MST_EDGE_CASE_MAP[i];
-                        sides.push(Segment2I {
-                            from: edge.from + local,
-                            to: edge.to + local,
-                        })
+        for i in 0..self.texels.len() {
+            let local = texel_index_to_local(i);
+            for (side, neighbour) in border.outside_neighbours(&local).iter().enumerate() {
+                match neighbour {
+                    Some(BorderTexel2D::Collider(_)) => snapshot.neighbour_mask[i] |= 1 << side,
+                    Some(_) => (),
+                    None => {
+                        // Neighbour masks don't know about motion
+                        let offset = Self::NEIGHBOUR_OFFSET_VECTORS[side];
+                        let neighbour = local_to_texel_index(&(local + offset)).unwrap();
+                        if snapshot.colliders[neighbour].is_none() {
+                            snapshot.neighbour_mask[i] &= !(1 << side);
+                        }
                     }
                 }
-            } else {
-                continue;
             }
+        }
+        snapshot
+    }
+}
 
-            for side in sides {
-                // Check if the side can be attached to any island
-                // The naming of front and back are kind of misleading, and come from the VecDeque type.
-                // You can think of the front as the beginning of the island loop, and back the end.
+/// Copy of the collision state of a chunk, so that the colliders can be built off the main thread
+#[derive(Clone)]
+pub struct ChunkCollisionSnapshot2D {
+    /// Collider properties of the texels that take part in the collision, see `Chunk2D::is_collider`
+    pub colliders: [Option<TexelColliderProperties2D>; Chunk2D::SIZE_X * Chunk2D::SIZE_Y],
+    /// Bitmask of neighbours that take part in the collision, including the texels of the neighbouring chunks
+    pub neighbour_mask: [NeighbourMask; Chunk2D::SIZE_X * Chunk2D::SIZE_Y],
+    pub border: ChunkBorder2D,
+}
 
-                // Connect to an island if possible, otherwise create a new island
-                {
-                    let mut connected_to: Option<&mut Island> = None;
-                    for island in islands.iter_mut() {
-                        if island.back().is_some() && island.back().unwrap().to == side.from {
-                            connected_to = Some(island);
-                        }
-                    }
+impl ChunkCollisionSnapshot2D {
+    /// Collider outlines, split so that each outline has a single set of collider properties
+    pub fn create_collision_data(&self) -> Vec<ColliderOutline2D> {
+        let bounds = Rect2I::new(Vector2I::ZERO, Chunk2D::SIZE);
+        let mut outlines = vec![];
+        for (properties, mut segments) in self.collision_segments() {
+            if properties.one_way {
+                // Only the surfaces facing up. The segments go clockwise around the solid texels.
+                segments.retain(|segment| segment.diff().x > 0);
+            }
+            outlines.extend(
+                islands_to_polylines(trace_islands(&segments, &bounds))
+                    .into_iter()
+                    .map(|polyline| ColliderOutline2D {
+                        properties,
+                        polyline,
+                    }),
+            );
+        }
+        outlines
+    }
 
-                    match connected_to {
-                        Some(back) => {
-                            back.push_back(side);
-                        }
-                        None => {
-                            let mut island: Island = Island::new();
-                            island.push_back(side);
-                            islands.push(island);
-                        }
-                    }
-                }
+    /// Marching square segments of the chunk, grouped by the collider properties of the texels they surround.
+    ///
+    /// Empty texels also take the texels of the neighbouring chunks into account, so each surface between a solid
+    /// and an empty texel is created exactly once, by the chunk of the empty texel. Solid texels only create
+    /// segments on the edges where there is no neighbouring chunk.
+    fn collision_segments(&self) -> Vec<(TexelColliderProperties2D, Vec<Segment2I>)> {
+        let mut groups: Vec<(TexelColliderProperties2D, Vec<Segment2I>)> = vec![];
+        let mut push = |properties: TexelColliderProperties2D, segment: Segment2I| match groups
+            .iter_mut()
+            .find(|(other, _)| *other == properties)
+        {
+            Some((_, segments)) => segments.push(segment),
+            None => groups.push((properties, vec![segment])),
+        };
 
-                // Find connected islands
-                loop {
-                    let mut merge_index: Option<usize> = None;
-                    'outer: for i in 0..islands.len() {
-                        for j in 0..islands.len() {
-                            if i == j {
-                                continue;
-                            }
-                            if islands[i].back().is_some()
-                                && islands[j].front().is_some()
-                                && islands[i].back().unwrap().to == islands[j].front().unwrap().from
-                            {
-                                merge_index = Some(i);
-                                break 'outer;
-                            }
+        for i in 0..self.colliders.len() {
+            let local = texel_index_to_local(i);
+            let outside = self.border.outside_neighbours(&local);
+            match self.colliders[i] {
+                None => {
+                    let mask = self.neighbour_mask[i];
+                    for side in MST_CASE_MAP[mask as usize].iter() {
+                        let facing = facing_side(side, mask);
+                        let properties = match outside[facing] {
+                            Some(BorderTexel2D::Collider(properties)) => Some(properties),
+                            Some(_) => None,
+                            None => local_to_texel_index(
+                                &(local + Chunk2D::NEIGHBOUR_OFFSET_VECTORS[facing]),
+                            )
+                            .and_then(|neighbour| self.colliders[neighbour]),
+                        };
+                        if let Some(properties) = properties {
+                            push(
+                                properties,
+                                Segment2I {
+                                    from: side.from + local,
+                                    to: side.to + local,
+                                },
+                            );
                         }
                     }
-
-                    // Merge connected islands
-                    match merge_index {
-                        Some(index) => {
-                            let mut merge_from = islands.swap_remove(index);
-                            match islands.iter_mut().find(|island| match island.front() {
-                                Some(front) => front.from == merge_from.back().unwrap().to,
-                                None => false,
-                            }) {
-                                Some(merge_to) => loop {
-                                    match merge_from.pop_back() {
-                                        Some(segment) => merge_to.push_front(segment),
-                                        None => break,
-                                    }
+                }
+                Some(properties) => {
+                    for (side, neighbour) in outside.iter().enumerate() {
+                        if *neighbour == Some(BorderTexel2D::MissingChunk) {
+                            let edge = MST_EDGE_CASE_MAP[side];
+                            push(
+                                properties,
+                                Segment2I {
+                                    from: edge.from + local,
+                                    to: edge.to + local,
                                 },
-                                None => (),
-                            };
+                            );
                         }
-                        None => break,
                     }
                 }
             }
         }
+        groups
+    }
+}
 
-        let mut result: Vec<Vec<Vec2>> = Vec::with_capacity(islands.len());
-        for island in islands {
-            if island.len() < 4 {
-                continue;
+/// Side of an empty tile that a marching square segment of the tile faces, in the order of NEIGHBOUR_OFFSET_VECTORS.
+/// Diagonal segments face two sides, in which case the first one wins.
+fn facing_side(segment: &Segment2I, mask: NeighbourMask) -> usize {
+    // Middle of the segment relative to the center of the tile, in doubled coordinates
+    let middle = segment.from + segment.to - Vector2I::ONE;
+    (0..Chunk2D::NEIGHBOUR_OFFSET_VECTORS.len())
+        .filter(|side| mask & (1 << side) != 0)
+        .max_by_key(|side| {
+            let offset = Chunk2D::NEIGHBOUR_OFFSET_VECTORS[*side];
+            (middle.x * offset.x + middle.y * offset.y, Reverse(*side))
+        })
+        .unwrap_or(0)
+}
+
+/// Chain segments into islands. Islands are closed, except for chains that cross the edge of `bounds`.
+///
+/// Runs in linear time: outgoing segments are indexed by their starting vertex in a grid covering `bounds`.
+/// A vertex has more than one outgoing segment only where two islands touch diagonally. In that case the most
+/// clockwise turn is taken, which keeps the islands separate.
+pub fn trace_islands(segments: &[Segment2I], bounds: &Rect2I) -> Vec<Island> {
+    const NONE: usize = usize::MAX;
+    let mut outgoing = vec![[NONE; 2]; bounds.area()];
+    let mut incoming = vec![0u8; bounds.area()];
+    for (index, segment) in segments.iter().enumerate() {
+        let vertex = bounds
+            .index_of(&segment.from)
+            .expect("Segment outside of the tracing bounds");
+        let slot = if outgoing[vertex][0] == NONE { 0 } else { 1 };
+        outgoing[vertex][slot] = index;
+        if let Some(vertex) = bounds.index_of(&segment.to) {
+            incoming[vertex] += 1;
+        }
+    }
+
+    // Open chains are traced from their first segment, which starts from a vertex with more outgoing than
+    // incoming segments. The remaining segments form closed loops.
+    let chain_starts = segments.iter().enumerate().filter_map(|(index, segment)| {
+        let vertex = bounds.index_of(&segment.from).unwrap();
+        let outgoing_count = outgoing[vertex].iter().filter(|i| **i != NONE).count();
+        (outgoing_count > incoming[vertex] as usize).then_some(index)
+    });
+    let starts: Vec<usize> = chain_starts.chain(0..segments.len()).collect();
+
+    let mut used = vec![false; segments.len()];
+    let mut islands: Vec<Island> = Vec::new();
+    for start in starts {
+        if used[start] {
+            continue;
+        }
+
+        let mut island = Island::new();
+        let mut current = start;
+        loop {
+            used[current] = true;
+            let segment = segments[current];
+            island.push_back(segment);
+            if segment.to == segments[start].from {
+                break;
             }
-            let mut points: Vec<Vec2> = Vec::with_capacity(island.len() + 1);
-            points.push(Vec2::from(island.front().unwrap().from));
-            let mut current_angle: Option<f32> = None;
-            for side in island {
-                if current_angle.is_some() && (current_angle.unwrap() - side.angle()).abs() < 0.1 {
-                    let len = points.len();
-                    points[len - 1] = Vec2::from(side.to)
-                } else {
-                    current_angle = Some(side.angle());
-                    points.push(Vec2::from(side.to));
-                }
+
+            let next = match bounds.index_of(&segment.to) {
+                Some(vertex) => outgoing[vertex]
+                    .iter()
+                    .filter(|next| **next != NONE && !used[**next])
+                    .min_by(|a, b| {
+                        turn_angle(&segment, &segments[**a])
+                            .partial_cmp(&turn_angle(&segment, &segments[**b]))
+                            .unwrap()
+                    })
+                    .copied(),
+                None => None,
+            };
+            match next {
+                Some(next) => current = next,
+                None => break,
             }
-            result.push(points);
         }
-        result
+        islands.push(island);
     }
+    islands
+}
+
+/// Signed angle between two consecutive segments. Negative values turn clockwise.
+fn turn_angle(from: &Segment2I, to: &Segment2I) -> f32 {
+    let a = from.diff();
+    let b = to.diff();
+    let cross = (a.x * b.y - a.y * b.x) as f32;
+    let dot = (a.x * b.x + a.y * b.y) as f32;
+    cross.atan2(dot)
+}
+
+/// Convert islands to polylines, merging consecutive segments that point the same way
+pub fn islands_to_polylines(islands: Vec<Island>) -> Vec<Vec<Vec2>> {
+    let mut result: Vec<Vec<Vec2>> = Vec::with_capacity(islands.len());
+    for island in islands {
+        let is_closed = island.front().map(|s| s.from) == island.back().map(|s| s.to);
+        if island.is_empty() || (is_closed && island.len() < 4) {
+            continue;
+        }
+        let mut points: Vec<Vec2> = Vec::with_capacity(island.len() + 1);
+        points.push(Vec2::from(island.front().unwrap().from));
+        let mut current_angle: Option<f32> = None;
+        for side in island {
+            if current_angle.is_some() && (current_angle.unwrap() - side.angle()).abs() < 0.1 {
+                let len = points.len();
+                points[len - 1] = Vec2::from(side.to)
+            } else {
+                current_angle = Some(side.angle());
+                points.push(Vec2::from(side.to));
+            }
+        }
+        result.push(points);
+    }
+    result
 }
 
 pub fn chunk_spawner(
@@ -449,7 +763,9 @@ pub fn chunk_sprite_sync(
     >,
     chunk_query: Query<(Entity, &TerrainChunk2D), (With<TerrainChunkSpriteSync2D>, With<Sprite>)>,
     texture_query: Query<&Handle<Image>>,
+    mut metrics: ResMut<TerrainMetrics>,
 ) {
+    let start = Instant::now();
     let mut updated_chunks: Vec<(Entity, &TerrainChunk2D, Option<ChunkRect>)> = vec![];
 
     // Check for added components
@@ -493,13 +809,39 @@ pub fn chunk_sprite_sync(
         let image_data = chunk.create_texture_data();
         image.data = image_data;
     }
+
+    metrics.current.sprite_sync_ms += elapsed_ms(start);
+}
+
+/// Collider building task of a chunk
+pub struct ChunkColliderTask2D {
+    entity: Entity,
+    /// Snapshot version the task was started from
+    version: u64,
+    task: Task<Vec<ColliderOutline2D>>,
 }
 
-/// Create and update colliders for chunk as needed
+/// Create and update colliders for chunk as needed.
+///
+/// Colliders are built on the `AsyncComputeTaskPool` from a snapshot of the chunk. The old colliders stay in place
+/// until the new ones are ready. A chunk has at most one running task: if it changes again meanwhile, one follow-up
+/// task is queued once the running task has been applied. A result is only skipped if a newer snapshot has already
+/// been applied. Newly added chunks without colliders build their first colliders during the frame, so that they
+/// never go without.
 pub fn chunk_collision_sync(
     mut terrain_events: EventReader<TerrainEvent2D>,
     mut commands: Commands,
     terrain: Res<Terrain2D>,
+    settings: Res<ChunkColliderSettings2D>,
+    frame_counter: Res<FrameCounter>,
+    // Chunks with moving texels, and the frame on which the texels have settled if they stop moving
+    mut settling_chunks: Local<HashMap<Chunk2DIndex, u64>>,
+    mut tasks: Local<HashMap<Chunk2DIndex, ChunkColliderTask2D>>,
+    // Chunks that changed while their task was running
+    mut dirty_chunks: Local<HashSet<Chunk2DIndex>>,
+    // Latest snapshot version applied to each chunk
+    mut applied_versions: Local<HashMap<Chunk2DIndex, u64>>,
+    mut next_version: Local<u64>,
     added_chunk_query: Query<
         (Entity, &TerrainChunk2D),
         (With<TerrainChunkCollisionSync2D>, Changed<TerrainChunk2D>),
@@ -507,86 +849,367 @@ pub fn chunk_collision_sync(
     chunk_query: Query<(Entity, &TerrainChunk2D), With<TerrainChunkCollisionSync2D>>,
     child_query: Query<&Children>,
     collider_query: Query<&Collider>,
+    mut metrics: ResMut<TerrainMetrics>,
 ) {
-    let mut updated_chunks: Vec<(Entity, &TerrainChunk2D)> = vec![];
+    let start = Instant::now();
+
+    let mut updated_indices: HashSet<Chunk2DIndex> = HashSet::new();
+
+    // Apply finished colliders
+    tasks.retain(|chunk_index, task| {
+        let outlines = match future::block_on(future::poll_once(&mut task.task)) {
+            Some(outlines) => outlines,
+            None => return true,
+        };
+        let applied_version = applied_versions.get(chunk_index).copied().unwrap_or(0);
+        if task.version > applied_version && chunk_query.get(task.entity).is_ok() {
+            apply_chunk_colliders(
+                &mut commands,
+                task.entity,
+                &outlines,
+                &child_query,
+                &collider_query,
+            );
+            applied_versions.insert(*chunk_index, task.version);
+            metrics.current.collider_rebuilds += 1;
+        }
+        if dirty_chunks.remove(chunk_index) {
+            updated_indices.insert(*chunk_index);
+        }
+        false
+    });
 
     // Check for added components
-    for (added_entity, added_chunk) in added_chunk_query.iter() {
-        updated_chunks.push((added_entity, added_chunk));
+    let mut added_indices: HashSet<Chunk2DIndex> = HashSet::new();
+    for (_, added_chunk) in added_chunk_query.iter() {
+        updated_indices.insert(added_chunk.index);
+        added_indices.insert(added_chunk.index);
     }
 
     // Check for terrain events
     for event in terrain_events.iter() {
-        for (entity, chunk) in chunk_query.iter() {
-            let chunk_index = match event {
-                TerrainEvent2D::ChunkAdded(chunk_index) => {
-                    // The entity should not have the time to react to the event since it was just made
-                    // REM: This gets called when new chunk is instantiated with brush
-                    // println!("[chunk_collision_sync -> TerrainEvent2D::ChunkAdded] This probably shouldn't be firing, maybe the chunk was destroyed and immediately created? chunk: {chunk_index:?}");
-                    chunk_index
+        match event {
+            TerrainEvent2D::ChunkAdded(chunk_index) | TerrainEvent2D::ChunkRemoved(chunk_index) => {
+                // Neighbours either continue their outlines into the chunk or have to close them
+                updated_indices.insert(*chunk_index);
+                for offset in Chunk2D::NEIGHBOUR_OFFSET_VECTORS {
+                    updated_indices.insert(*chunk_index + offset);
                 }
-                TerrainEvent2D::TexelsUpdated(chunk_index, _) => chunk_index,
-                _ => continue,
-            };
+                match event {
+                    TerrainEvent2D::ChunkAdded(_) => {
+                        added_indices.insert(*chunk_index);
+                    }
+                    _ => {
+                        tasks.remove(chunk_index);
+                        dirty_chunks.remove(chunk_index);
+                        applied_versions.remove(chunk_index);
+                    }
+                }
+            }
+            TerrainEvent2D::TexelsUpdated(chunk_index, _) => {
+                updated_indices.insert(*chunk_index);
+            }
+        }
+    }
 
-            if *chunk_index != chunk.index {
-                continue;
-            };
+    // Check for settled texels
+    settling_chunks.retain(|chunk_index, settle_frame| {
+        if *settle_frame > frame_counter.frame {
+            return true;
+        }
+        updated_indices.insert(*chunk_index);
+        false
+    });
 
-            updated_chunks.push((entity, chunk));
-        }
-    }
-
-    // let layer_membership = CollisionLayers::WORLD;
-
-    // REM: Kinda messy, partly due do how entity creation is timed
-    for (entity, chunk_component) in updated_chunks.iter() {
-        let chunk = terrain.index_to_chunk(&chunk_component.index).unwrap();
-        let new_islands = chunk.create_collision_data();
-
-        // Create new colliders
-        if let Ok(children) = child_query.get(*entity) {
-            // Chunk has children, new ones will be created and old ones components will be removed
-            for (index, island) in new_islands.iter().enumerate() {
-                if let Some(child) = children.get(index) {
-                    // Replace collider
-                    commands
-                        .entity(*child)
-                        .insert(Collider::polyline(island.clone(), None));
-                } else {
-                    // Create new child
-                    commands.entity(*entity).with_children(|builder| {
-                        builder
-                            .spawn(Collider::polyline(island.clone(), None))
-                            .insert(TransformBundle::default())
-                            .insert(CollisionGroups::new(CollisionLayers::WORLD, Group::ALL))
-                            .insert(Name::new(format!("Island #{}", index)));
-                    });
-                }
+    let updated_chunks: Vec<(Entity, &TerrainChunk2D)> = chunk_query
+        .iter()
+        .filter(|(_, chunk)| updated_indices.contains(&chunk.index))
+        .collect();
+
+    let task_pool = AsyncComputeTaskPool::get();
+    for (entity, chunk_component) in updated_chunks {
+        let chunk = match terrain.index_to_chunk(&chunk_component.index) {
+            Some(chunk) => chunk,
+            None => continue,
+        };
+        let motion = ChunkMotion2D {
+            simulation_frame: (frame_counter.frame % u8::MAX as u64) as u8 + 1,
+            settle_frames: settings.settle_frames,
+        };
+        if chunk.has_moving_texels(&motion) {
+            // Neighbours are included, since they may have left out the moving texels at the chunk edge
+            let settle_frame = frame_counter.frame + settings.settle_frames as u64;
+            settling_chunks.insert(chunk_component.index, settle_frame);
+            for offset in Chunk2D::NEIGHBOUR_OFFSET_VECTORS {
+                settling_chunks.insert(chunk_component.index + offset, settle_frame);
             }
-        } else {
-            // Chunk doesn't have a Children component yet
-            for (index, island) in new_islands.iter().enumerate() {
-                commands.entity(*entity).with_children(|builder| {
-                    builder
-                        .spawn(Collider::polyline(island.clone(), None))
-                        .insert(TransformBundle::default())
-                        .insert(CollisionGroups::new(CollisionLayers::WORLD, Group::ALL))
-                        .insert(Name::new(format!("Island #{}", index)));
-                });
+        }
+
+        let first_colliders = added_indices.contains(&chunk_component.index)
+            && !has_colliders(entity, &child_query, &collider_query);
+        if !settings.blocking && !first_colliders && tasks.contains_key(&chunk_component.index) {
+            // Rebuilt from a fresh snapshot once the running task has been applied
+            dirty_chunks.insert(chunk_component.index);
+            continue;
+        }
+
+        let border = terrain.chunk_border(&chunk_component.index, &motion);
+        let snapshot = chunk.collision_snapshot(&border, &motion);
+        *next_version += 1;
+        let version = *next_version;
+        if settings.blocking || first_colliders {
+            // A running task is older, so its result will be skipped
+            dirty_chunks.remove(&chunk_component.index);
+            let outlines = settings.simplify(snapshot.create_collision_data());
+            apply_chunk_colliders(
+                &mut commands,
+                entity,
+                &outlines,
+                &child_query,
+                &collider_query,
+            );
+            applied_versions.insert(chunk_component.index, version);
+            metrics.current.collider_rebuilds += 1;
+            continue;
+        }
+        let settings = settings.clone();
+        let task =
+            task_pool.spawn(async move { settings.simplify(snapshot.create_collision_data()) });
+        tasks.insert(
+            chunk_component.index,
+            ChunkColliderTask2D {
+                entity,
+                version,
+                task,
+            },
+        );
+    }
+
+    metrics.current.collision_sync_ms += elapsed_ms(start);
+}
+
+/// Does the chunk have any collider children
+fn has_colliders(
+    entity: Entity,
+    child_query: &Query<&Children>,
+    collider_query: &Query<&Collider>,
+) -> bool {
+    child_query.get(entity).map_or(false, |children| {
+        children
+            .iter()
+            .any(|child| collider_query.get(*child).is_ok())
+    })
+}
+
+/// Replace the collider children of the chunk with the outlines
+fn apply_chunk_colliders(
+    commands: &mut Commands,
+    entity: Entity,
+    outlines: &[ColliderOutline2D],
+    child_query: &Query<&Children>,
+    collider_query: &Query<&Collider>,
+) {
+    let children = child_query.get(entity).ok();
+
+    // Create new colliders. Existing children are reused, old components are replaced.
+    for (index, outline) in outlines.iter().enumerate() {
+        let bundle = (
+            Collider::polyline(outline.polyline.clone(), None),
+            Friction::coefficient(outline.properties.friction),
+            Restitution::coefficient(outline.properties.restitution),
+            CollisionGroups::new(CollisionLayers::WORLD, outline.properties.collision_filter),
+        );
+        let child = match children.and_then(|children| children.get(index)) {
+            Some(child) => {
+                commands.entity(*child).insert(bundle);
+                *child
+            }
+            None => {
+                let child = commands
+                    .spawn(bundle)
+                    .insert(TransformBundle::default())
+                    .insert(Name::new(format!("Island #{}", index)))
+                    .id();
+                commands.entity(entity).add_child(child);
+                child
             }
         };
+        if outline.properties.one_way {
+            commands.entity(child).insert(OneWayCollider);
+        } else {
+            commands.entity(child).remove::<OneWayCollider>();
+        }
+    }
+
+    // Remove extra children.
+    // Leaving them seems to cause weird problems with rapier when re-adding the collider. The collider is ignored until something else is updated.
+    if let Some(children) = children {
+        for (index, child) in children.iter().enumerate() {
+            if let Ok(_) = collider_query.get(*child) {
+                if index >= outlines.len() {
+                    commands.entity(*child).despawn_recursive();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Random;
+
+    const STONE: TexelID = 12;
+
+    /// Chunk with randomly placed stone. The density varies between the rounds to get both sparse and cave-like
+    /// chunks.
+    fn random_chunk(random: &mut Random, density: f64) -> Chunk2D {
+        let mut chunk = Chunk2D::new();
+        for local in Chunk2D::xy_vec() {
+            if random.chance(density) {
+                chunk.set_texel(
+                    &local,
+                    Texel2D {
+                        id: STONE,
+                        ..default()
+                    },
+                    None,
+                );
+            }
+        }
+        chunk
+    }
+
+    fn chunk_segments(chunk: &Chunk2D) -> Vec<Segment2I> {
+        chunk
+            .collision_snapshot(&ChunkBorder2D::default(), &ChunkMotion2D::NONE)
+            .collision_segments()
+            .into_iter()
+            .flat_map(|(_, segments)| segments)
+            .collect()
+    }
 
-        // Remove extra children.
-        // Leaving them seems to cause weird problems with rapier when re-adding the collider. The collider is ignored until something else is updated.
-        for children in child_query.get(*entity) {
-            for (index, child) in children.iter().enumerate() {
-                if let Ok(_) = collider_query.get(*child) {
-                    if index >= new_islands.len() {
-                        commands.entity(*child).despawn_recursive();
+    /// The island merge that `trace_islands` replaced, kept as a reference. Quadratic in the number of islands.
+    fn reference_islands(segments: &[Segment2I]) -> Vec<Island> {
+        let mut islands: Vec<Island> = Vec::new();
+        for side in segments.iter().copied() {
+            // Connect to an island if possible, otherwise create a new island
+            match islands
+                .iter_mut()
+                .rev()
+                .find(|island| island.back().map_or(false, |back| back.to == side.from))
+            {
+                Some(island) => island.push_back(side),
+                None => islands.push(Island::from([side])),
+            }
+
+            // Merge connected islands
+            loop {
+                let merge_index = (0..islands.len()).find(|i| {
+                    (0..islands.len()).any(|j| {
+                        *i != j
+                            && islands[*i].back().map(|back| back.to)
+                                == islands[j].front().map(|front| front.from)
+                    })
+                });
+                let mut merge_from = match merge_index {
+                    Some(index) => islands.swap_remove(index),
+                    None => break,
+                };
+                let end = merge_from.back().unwrap().to;
+                if let Some(merge_to) = islands
+                    .iter_mut()
+                    .find(|island| island.front().map_or(false, |front| front.from == end))
+                {
+                    while let Some(segment) = merge_from.pop_back() {
+                        merge_to.push_front(segment);
                     }
                 }
             }
         }
+        islands
+    }
+
+    fn segment_key(segment: &Segment2I) -> (i32, i32, i32, i32) {
+        (segment.from.x, segment.from.y, segment.to.x, segment.to.y)
+    }
+
+    /// Islands as comparable loops: each rotated to start from its smallest segment, sorted
+    fn normalized_loops(islands: &[Island]) -> Vec<Vec<(i32, i32, i32, i32)>> {
+        let mut loops: Vec<Vec<_>> = islands
+            .iter()
+            .map(|island| {
+                let mut keys: Vec<_> = island.iter().map(segment_key).collect();
+                let first = (0..keys.len()).min_by_key(|i| keys[*i]).unwrap_or(0);
+                keys.rotate_left(first);
+                keys
+            })
+            .collect();
+        loops.sort();
+        loops
+    }
+
+    /// Winding number of the islands around a point. Positive is counter-clockwise.
+    fn winding_number(islands: &[Island], point: Vec2) -> i32 {
+        let mut winding = 0;
+        for segment in islands.iter().flatten() {
+            let (from, to) = (Vec2::from(segment.from), Vec2::from(segment.to));
+            let side = (to - from).perp_dot(point - from);
+            if from.y <= point.y && to.y > point.y && side > 0.0 {
+                winding += 1;
+            } else if from.y > point.y && to.y <= point.y && side < 0.0 {
+                winding -= 1;
+            }
+        }
+        winding
+    }
+
+    #[test]
+    fn trace_islands_matches_reference() {
+        let mut random = Random::new(31);
+        let bounds = Rect2I::new(Vector2I::ZERO, Chunk2D::SIZE);
+        for round in 0..200 {
+            let density = [0.2, 0.4, 0.5, 0.6, 0.8][round % 5];
+            let segments = chunk_segments(&random_chunk(&mut random, density));
+            let islands = trace_islands(&segments, &bounds);
+
+            // Without neighbouring chunks every island is closed
+            for island in islands.iter() {
+                assert_eq!(
+                    island.front().map(|segment| segment.from),
+                    island.back().map(|segment| segment.to),
+                    "round {round}: open island"
+                );
+            }
+
+            assert_eq!(
+                normalized_loops(&islands),
+                normalized_loops(&reference_islands(&segments)),
+                "round {round}: islands differ from the reference"
+            );
+
+            // Clockwise around the solid texels: the solid side is on the right of every segment, so points just
+            // to the right are inside the outlines exactly once, with a clockwise winding
+            for segment in islands.iter().flatten() {
+                let diff = Vec2::from(segment.diff());
+                let middle = (Vec2::from(segment.from) + Vec2::from(segment.to)) / 2.0;
+                let right = middle + Vec2::new(diff.y, -diff.x) * 0.25;
+                assert_eq!(
+                    winding_number(&islands, right),
+                    -1,
+                    "round {round}: {segment} doesn't go clockwise"
+                );
+            }
+
+            // Every segment is used exactly once
+            let mut traced: Vec<_> = islands.iter().flatten().map(segment_key).collect();
+            let mut expected: Vec<_> = segments.iter().map(segment_key).collect();
+            traced.sort();
+            expected.sort();
+            assert_eq!(
+                traced, expected,
+                "round {round}: segments lost or duplicated"
+            );
+        }
     }
 }
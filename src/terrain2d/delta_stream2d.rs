@@ -0,0 +1,407 @@
+use std::{fmt, mem};
+
+use super::*;
+use crate::util::Vector2I;
+
+/// What made a texel change, as recorded by the delta stream
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum TexelChangeCause2D {
+    /// Anything not covered by the other causes, e.g. applied deltas
+    #[default]
+    Other,
+    /// `Terrain2D::step`
+    Simulation,
+    /// Terrain edits, including undo and redo
+    Edit,
+    /// World generation, e.g. placed prefabs. Generated chunks are reported by `TerrainEvent2D::ChunkAdded` instead.
+    Generation,
+    /// Images loaded with `Terrain2D::import_png`
+    Import,
+}
+
+impl TexelChangeCause2D {
+    const ALL: [TexelChangeCause2D; 5] = [
+        TexelChangeCause2D::Other,
+        TexelChangeCause2D::Simulation,
+        TexelChangeCause2D::Edit,
+        TexelChangeCause2D::Generation,
+        TexelChangeCause2D::Import,
+    ];
+
+    fn to_bits(self) -> u8 {
+        self as u8
+    }
+
+    fn from_bits(bits: u8) -> Option<TexelChangeCause2D> {
+        Self::ALL.get(bits as usize).copied()
+    }
+}
+
+/// A single texel change in the delta stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TexelDelta2D {
+    pub global: Vector2I,
+    pub before: Texel2D,
+    pub after: Texel2D,
+    pub cause: TexelChangeCause2D,
+}
+
+/// Texel changes of a tick, in the order they were made. Sent as an event while the delta stream is enabled.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TerrainDelta2D {
+    pub tick: u64,
+    pub changes: Vec<TexelDelta2D>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TerrainDeltaError2D {
+    UnexpectedEnd,
+    VarintOverflow,
+    InvalidCause(u8),
+}
+
+impl fmt::Display for TerrainDeltaError2D {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerrainDeltaError2D::UnexpectedEnd => write!(f, "unexpected end of delta data"),
+            TerrainDeltaError2D::VarintOverflow => write!(f, "varint is too long"),
+            TerrainDeltaError2D::InvalidCause(bits) => write!(f, "invalid change cause {bits}"),
+        }
+    }
+}
+
+impl std::error::Error for TerrainDeltaError2D {}
+
+// Flags byte of an encoded change: the cause in the low bits, followed by whether the densities are stored
+const CAUSE_MASK: u8 = 0b0000_0111;
+const BEFORE_DENSITY: u8 = 0b0000_1000;
+const AFTER_DENSITY: u8 = 0b0001_0000;
+
+impl TerrainDelta2D {
+    /// Append the compact binary form of the delta.
+    ///
+    /// The tick and change count are varints. Each change stores its position as zigzag varints relative to the
+    /// previous change, a flags byte with the cause, and the texel IDs. Densities are only stored when they differ
+    /// from the default, so a typical change takes 4-6 bytes.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.tick);
+        write_varint(out, self.changes.len() as u64);
+        let mut previous = Vector2I::ZERO;
+        let default_density = Texel2D::default().density;
+        for change in self.changes.iter() {
+            // Wrapping, so that any two positions have an offset
+            write_varint(out, zigzag(change.global.x.wrapping_sub(previous.x)));
+            write_varint(out, zigzag(change.global.y.wrapping_sub(previous.y)));
+            previous = change.global;
+
+            let mut flags = change.cause.to_bits();
+            if change.before.density != default_density {
+                flags |= BEFORE_DENSITY;
+            }
+            if change.after.density != default_density {
+                flags |= AFTER_DENSITY;
+            }
+            out.push(flags);
+            out.push(change.before.id);
+            if flags & BEFORE_DENSITY != 0 {
+                out.push(change.before.density);
+            }
+            out.push(change.after.id);
+            if flags & AFTER_DENSITY != 0 {
+                out.push(change.after.density);
+            }
+        }
+    }
+
+    /// Read a delta written by `encode` from the start of the data, advancing it past the delta
+    pub fn decode(data: &mut &[u8]) -> Result<TerrainDelta2D, TerrainDeltaError2D> {
+        let tick = read_varint(data)?;
+        let len = read_varint(data)? as usize;
+        // Every change takes at least five bytes, which also keeps corrupt lengths from allocating too much
+        let mut changes = Vec::with_capacity(len.min(data.len() / 5));
+        let mut previous = Vector2I::ZERO;
+        let default_density = Texel2D::default().density;
+        for _ in 0..len {
+            let x = unzigzag(read_varint(data)?);
+            let y = unzigzag(read_varint(data)?);
+            let global = Vector2I::new(previous.x.wrapping_add(x), previous.y.wrapping_add(y));
+            previous = global;
+
+            let flags = read_byte(data)?;
+            let cause = TexelChangeCause2D::from_bits(flags & CAUSE_MASK)
+                .ok_or(TerrainDeltaError2D::InvalidCause(flags & CAUSE_MASK))?;
+            let mut read_texel = |has_density: bool| -> Result<Texel2D, TerrainDeltaError2D> {
+                Ok(Texel2D {
+                    id: read_byte(data)?,
+                    density: match has_density {
+                        true => read_byte(data)?,
+                        false => default_density,
+                    },
+                })
+            };
+            let before = read_texel(flags & BEFORE_DENSITY != 0)?;
+            let after = read_texel(flags & AFTER_DENSITY != 0)?;
+            changes.push(TexelDelta2D {
+                global,
+                before,
+                after,
+                cause,
+            });
+        }
+        Ok(TerrainDelta2D { tick, changes })
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_byte(data: &mut &[u8]) -> Result<u8, TerrainDeltaError2D> {
+    let (byte, rest) = data
+        .split_first()
+        .ok_or(TerrainDeltaError2D::UnexpectedEnd)?;
+    *data = rest;
+    Ok(*byte)
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u64, TerrainDeltaError2D> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = read_byte(data)?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(TerrainDeltaError2D::VarintOverflow)
+}
+
+fn zigzag(value: i32) -> u64 {
+    ((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+fn unzigzag(value: u64) -> i32 {
+    let value = value as u32;
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+/// Texel changes collected for the current tick
+#[derive(Default)]
+pub(crate) struct TerrainDeltaStream2D {
+    changes: Vec<TexelDelta2D>,
+}
+
+impl Terrain2D {
+    /// Start recording every texel change, see `take_delta`. The stream is off by default and costs nothing then.
+    pub fn enable_delta_stream(&mut self) {
+        if self.delta_stream.is_none() {
+            self.delta_stream = Some(TerrainDeltaStream2D::default());
+        }
+    }
+
+    /// Stop recording and drop the changes that were not taken yet
+    pub fn disable_delta_stream(&mut self) {
+        self.delta_stream = None;
+    }
+
+    pub fn is_delta_stream_enabled(&self) -> bool {
+        self.delta_stream.is_some()
+    }
+
+    /// Cause recorded for the following changes. Returns the previous cause, which the caller should restore.
+    pub fn set_change_cause(&mut self, cause: TexelChangeCause2D) -> TexelChangeCause2D {
+        mem::replace(&mut self.change_cause, cause)
+    }
+
+    /// Run `f` with the change cause set, restoring the previous cause afterwards
+    pub fn with_change_cause<R>(
+        &mut self,
+        cause: TexelChangeCause2D,
+        f: impl FnOnce(&mut Terrain2D) -> R,
+    ) -> R {
+        let previous = self.set_change_cause(cause);
+        let result = f(self);
+        self.set_change_cause(previous);
+        result
+    }
+
+    /// Changes recorded since the previous call, as the delta of the tick. None if the stream is disabled.
+    pub fn take_delta(&mut self, tick: u64) -> Option<TerrainDelta2D> {
+        self.delta_stream.as_mut().map(|stream| TerrainDelta2D {
+            tick,
+            changes: mem::take(&mut stream.changes),
+        })
+    }
+
+    /// Set the texels to their state after the delta, e.g. to mirror a remote terrain
+    pub fn apply_delta(&mut self, delta: &TerrainDelta2D) -> Vec<TexelChange2D> {
+        self.set_texels(
+            delta
+                .changes
+                .iter()
+                .map(|change| (change.global, change.after)),
+        )
+    }
+
+    pub(crate) fn record_delta(&mut self, global: Vector2I, before: Texel2D, after: Texel2D) {
+        if let Some(stream) = &mut self.delta_stream {
+            stream.changes.push(TexelDelta2D {
+                global,
+                before,
+                after,
+                cause: self.change_cause,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texel(id: TexelID, density: u8) -> Texel2D {
+        Texel2D { id, density }
+    }
+
+    fn encoded(delta: &TerrainDelta2D) -> Vec<u8> {
+        let mut data = vec![];
+        delta.encode(&mut data);
+        data
+    }
+
+    fn assert_round_trip(delta: &TerrainDelta2D) {
+        let data = encoded(delta);
+        let mut rest = data.as_slice();
+        assert_eq!(TerrainDelta2D::decode(&mut rest).as_ref(), Ok(delta));
+        assert!(rest.is_empty(), "{} bytes left", rest.len());
+    }
+
+    /// Changes with every cause, far apart and negative positions, and default and other densities
+    fn varied_delta() -> TerrainDelta2D {
+        let positions = [
+            Vector2I::new(0, 0),
+            Vector2I::new(-5, -7),
+            Vector2I::new(1_000_000, -3),
+            Vector2I::new(i32::MIN, i32::MAX),
+            Vector2I::new(i32::MAX, i32::MIN),
+            Vector2I::new(-1, 1),
+            Vector2I::new(-1, 1),
+        ];
+        let densities = [u8::MAX, 0, 17, 254];
+        let changes = positions
+            .iter()
+            .enumerate()
+            .flat_map(|(i, global)| {
+                TexelChangeCause2D::ALL
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(j, cause)| TexelDelta2D {
+                        global: *global,
+                        before: texel(j as TexelID, densities[(i + j) % densities.len()]),
+                        after: texel(i as TexelID + 11, densities[(i + 2 * j) % densities.len()]),
+                        cause,
+                    })
+            })
+            .collect();
+        TerrainDelta2D {
+            tick: u64::MAX,
+            changes,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        assert_round_trip(&varied_delta());
+        assert_round_trip(&TerrainDelta2D::default());
+        assert_round_trip(&TerrainDelta2D {
+            tick: 300,
+            changes: vec![],
+        });
+    }
+
+    #[test]
+    fn consecutive_deltas_decode_in_order() {
+        let deltas = [
+            varied_delta(),
+            TerrainDelta2D::default(),
+            TerrainDelta2D {
+                tick: 1,
+                changes: varied_delta().changes[..3].to_vec(),
+            },
+        ];
+        let mut data = vec![];
+        for delta in deltas.iter() {
+            delta.encode(&mut data);
+        }
+        let mut rest = data.as_slice();
+        for delta in deltas.iter() {
+            assert_eq!(TerrainDelta2D::decode(&mut rest).as_ref(), Ok(delta));
+        }
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn default_densities_are_not_stored() {
+        let change = |density| TerrainDelta2D {
+            tick: 0,
+            changes: vec![TexelDelta2D {
+                global: Vector2I::ZERO,
+                before: texel(0, density),
+                after: texel(1, density),
+                cause: TexelChangeCause2D::Edit,
+            }],
+        };
+        assert_eq!(encoded(&change(u8::MAX)).len(), 7);
+        assert_eq!(encoded(&change(3)).len(), 9);
+    }
+
+    #[test]
+    fn truncated_input() {
+        let data = encoded(&varied_delta());
+        for len in 0..data.len() {
+            let mut rest = &data[..len];
+            assert_eq!(
+                TerrainDelta2D::decode(&mut rest),
+                Err(TerrainDeltaError2D::UnexpectedEnd),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_cause() {
+        for cause in [5, 7] {
+            // Tick, one change at the origin, flags, and both texel IDs
+            let data = [0, 1, 0, 0, cause, 0, 0];
+            assert_eq!(
+                TerrainDelta2D::decode(&mut data.as_slice()),
+                Err(TerrainDeltaError2D::InvalidCause(cause))
+            );
+        }
+    }
+
+    #[test]
+    fn overlong_varint() {
+        let mut data = vec![0x80; 10];
+        data.push(0);
+        assert_eq!(
+            TerrainDelta2D::decode(&mut data.as_slice()),
+            Err(TerrainDeltaError2D::VarintOverflow)
+        );
+
+        // The longest valid varint takes ten bytes
+        let mut data = vec![0xff; 9];
+        data.extend([0x01, 0]);
+        assert_eq!(
+            TerrainDelta2D::decode(&mut data.as_slice()),
+            Ok(TerrainDelta2D {
+                tick: u64::MAX,
+                changes: vec![],
+            })
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    mem,
+};
+
+use super::*;
+use crate::util::Vector2I;
+
+/// Changes made by a single stroke, e.g. everything painted while a mouse button was held
+#[derive(Clone, Debug, Default)]
+pub struct EditStroke2D {
+    pub changes: Vec<TexelChange2D>,
+}
+
+impl EditStroke2D {
+    fn bytes(&self) -> usize {
+        self.changes.len() * mem::size_of::<TexelChange2D>()
+    }
+}
+
+/// Undo and redo journal for edits made through `TerrainEditHistory2D::apply_edit`.
+///
+/// The history is cleared when something else, like the simulation, changes texels that the history would restore.
+#[derive(Resource)]
+pub struct TerrainEditHistory2D {
+    undo: VecDeque<EditStroke2D>,
+    redo: Vec<EditStroke2D>,
+    stroke: Option<EditStroke2D>,
+    /// Texels touched by the history and their expected current state
+    touched: HashMap<Vector2I, Texel2D>,
+    /// Memory limit of the recorded changes in bytes, including the active stroke. The oldest strokes are forgotten
+    /// first. A stroke that grows past the limit continues as a new undo step, so strokes are only forgotten whole.
+    pub max_bytes: usize,
+}
+
+impl Default for TerrainEditHistory2D {
+    fn default() -> Self {
+        TerrainEditHistory2D {
+            undo: VecDeque::new(),
+            redo: vec![],
+            stroke: None,
+            touched: HashMap::new(),
+            max_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl TerrainEditHistory2D {
+    /// Group the following edits into a single undo step, until `end_stroke`
+    pub fn begin_stroke(&mut self) {
+        self.end_stroke();
+        self.stroke = Some(EditStroke2D::default());
+    }
+
+    pub fn end_stroke(&mut self) {
+        if let Some(stroke) = self.stroke.take() {
+            if !stroke.changes.is_empty() {
+                self.undo.push_back(stroke);
+                self.enforce_limit();
+            }
+        }
+    }
+
+    /// Apply the edit and record it. Outside of a stroke, the edit is an undo step of its own.
+    pub fn apply_edit(
+        &mut self,
+        terrain: &mut Terrain2D,
+        edit: &TerrainEdit2D,
+    ) -> Vec<TexelChange2D> {
+        let changes = terrain.apply_edit(edit);
+        self.record(&changes);
+        changes
+    }
+
+    /// Record changes made to the terrain by an edit
+    pub fn record(&mut self, changes: &[TexelChange2D]) {
+        if changes.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        for change in changes.iter() {
+            self.touched.insert(change.global, change.after);
+        }
+        match &mut self.stroke {
+            Some(stroke) => {
+                stroke.changes.extend_from_slice(changes);
+                if self.bytes() > self.max_bytes {
+                    self.begin_stroke();
+                }
+            }
+            None => {
+                self.undo.push_back(EditStroke2D {
+                    changes: changes.to_vec(),
+                });
+                self.enforce_limit();
+            }
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.stroke
+            .as_ref()
+            .map_or(false, |stroke| !stroke.changes.is_empty())
+            || !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Restore the texels changed by the latest stroke. Returns false if there was nothing to undo.
+    pub fn undo(&mut self, terrain: &mut Terrain2D) -> bool {
+        self.end_stroke();
+        let stroke = match self.undo.pop_back() {
+            Some(stroke) => stroke,
+            None => return false,
+        };
+        self.restore(
+            terrain,
+            stroke
+                .changes
+                .iter()
+                .rev()
+                .map(|change| (change.global, change.before)),
+        );
+        self.redo.push(stroke);
+        true
+    }
+
+    /// Apply the latest undone stroke again. Returns false if there was nothing to redo.
+    pub fn redo(&mut self, terrain: &mut Terrain2D) -> bool {
+        self.end_stroke();
+        let stroke = match self.redo.pop() {
+            Some(stroke) => stroke,
+            None => return false,
+        };
+        self.restore(
+            terrain,
+            stroke
+                .changes
+                .iter()
+                .map(|change| (change.global, change.after)),
+        );
+        self.undo.push_back(stroke);
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.stroke = None;
+        self.touched.clear();
+    }
+
+    /// Clear the history if the texels no longer match what the history last left them as
+    pub fn validate(&mut self, terrain: &Terrain2D, rect: &Rect2I) {
+        if self.touched.is_empty() {
+            return;
+        }
+        let changed = rect.points().any(|global| {
+            self.touched.get(&global).map_or(false, |expected| {
+                terrain.get_texel(&global).unwrap_or_default() != *expected
+            })
+        });
+        if changed {
+            // Edits made after this still belong to the active stroke
+            let stroke = self.stroke.is_some();
+            self.clear();
+            if stroke {
+                self.stroke = Some(EditStroke2D::default());
+            }
+        }
+    }
+
+    fn restore(
+        &mut self,
+        terrain: &mut Terrain2D,
+        texels: impl Iterator<Item = (Vector2I, Texel2D)>,
+    ) {
+        // set_texels marks the affected chunks dirty, which wakes them up for the simulation
+        let changes = terrain.with_change_cause(TexelChangeCause2D::Edit, |terrain| {
+            terrain.set_texels(texels)
+        });
+        for change in changes {
+            self.touched.insert(change.global, change.after);
+        }
+    }
+
+    /// Memory used by the recorded changes
+    fn bytes(&self) -> usize {
+        self.undo
+            .iter()
+            .chain(self.redo.iter())
+            .chain(self.stroke.iter())
+            .map(|stroke| stroke.bytes())
+            .sum()
+    }
+
+    fn enforce_limit(&mut self) {
+        let mut bytes = self.bytes();
+        if bytes <= self.max_bytes {
+            return;
+        }
+        while bytes > self.max_bytes {
+            match self.undo.pop_front() {
+                Some(stroke) => bytes -= stroke.bytes(),
+                None => break,
+            }
+        }
+        let remaining: HashSet<Vector2I> = self
+            .undo
+            .iter()
+            .chain(self.redo.iter())
+            .chain(self.stroke.iter())
+            .flat_map(|stroke| stroke.changes.iter().map(|change| change.global))
+            .collect();
+        self.touched.retain(|global, _| remaining.contains(global));
+    }
+}
+
+/// Clear the edit history when the simulation changes texels that the history has touched
+pub fn edit_history_validation(
+    mut terrain_events: EventReader<TerrainEvent2D>,
+    terrain: Res<Terrain2D>,
+    mut history: ResMut<TerrainEditHistory2D>,
+) {
+    for event in terrain_events.iter() {
+        if let TerrainEvent2D::TexelsUpdated(index, rect) = event {
+            let origin = chunk_index_to_global(index);
+            history.validate(&terrain, &Rect2I::new(origin + rect.min, origin + rect.max));
+        }
+    }
+}
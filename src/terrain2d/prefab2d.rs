@@ -0,0 +1,451 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use super::*;
+use crate::util::{Random, Rect2I};
+
+/// What a single character of a prefab drawing stands for
+#[derive(Clone, Debug)]
+pub enum PrefabPaletteEntry2D {
+    /// Cell is not part of the prefab, stamping leaves the terrain untouched
+    Transparent,
+    Texel(TexelID),
+    /// Empty texel with a named entity spawn marker
+    Marker(Cow<'static, str>),
+}
+
+/// Hand-made structure that can be stamped into the terrain
+#[derive(Clone, Debug)]
+pub struct Prefab2D {
+    pub name: Cow<'static, str>,
+    pub size: Vector2I,
+    /// Row-major from the bottom-left corner. None means the cell is transparent.
+    pub cells: Vec<Option<TexelID>>,
+    /// Positions are relative to the bottom-left corner of the prefab
+    pub markers: Vec<PrefabMarker2D>,
+}
+
+/// Named position for spawning entities, e.g. loot or enemies inside a vault
+#[derive(Clone, Debug, Default, Component, Reflect)]
+#[reflect(Component)]
+pub struct PrefabMarker2D {
+    pub name: Cow<'static, str>,
+    pub position: Vector2I,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PrefabRotation2D {
+    #[default]
+    None,
+    /// 90 degrees counter-clockwise
+    Quarter,
+    Half,
+    ThreeQuarters,
+}
+
+/// Mirroring is applied before rotation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrefabTransform2D {
+    pub rotation: PrefabRotation2D,
+    pub mirror_x: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StampMode2D {
+    /// Write every cell. Transparent cells clear the terrain.
+    #[default]
+    Overwrite,
+    /// Write non-transparent cells only where the terrain is empty
+    FillEmpty,
+    /// Write non-transparent cells, leave the rest of the terrain untouched
+    Masked,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PrefabError2D {
+    /// Row had a different width than the first row
+    UnevenRow { row: usize },
+    /// Character is missing from the palette
+    UnknownCharacter {
+        character: char,
+        row: usize,
+        column: usize,
+    },
+}
+
+impl fmt::Display for PrefabError2D {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrefabError2D::UnevenRow { row } => write!(f, "prefab row {row} has uneven width"),
+            PrefabError2D::UnknownCharacter {
+                character,
+                row,
+                column,
+            } => write!(
+                f,
+                "unknown prefab character '{character}' at row {row}, column {column}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PrefabError2D {}
+
+impl Prefab2D {
+    /// Create a prefab from a character drawing. The first row is the top of the prefab.
+    ///
+    /// ```ignore
+    /// let vault = Prefab2D::from_rows(
+    ///     "vault",
+    ///     &[('#', PrefabPaletteEntry2D::Texel(13)), ('.', PrefabPaletteEntry2D::Texel(0))],
+    ///     &["#####", "#...#", "#####"],
+    /// )?;
+    /// ```
+    pub fn from_rows(
+        name: impl Into<Cow<'static, str>>,
+        palette: &[(char, PrefabPaletteEntry2D)],
+        rows: &[&str],
+    ) -> Result<Prefab2D, PrefabError2D> {
+        let width = rows.first().map_or(0, |row| row.chars().count());
+        let height = rows.len();
+        let mut cells = vec![None; width * height];
+        let mut markers = vec![];
+
+        for (row, line) in rows.iter().enumerate() {
+            if line.chars().count() != width {
+                return Err(PrefabError2D::UnevenRow { row });
+            }
+            let y = height - 1 - row;
+            for (column, character) in line.chars().enumerate() {
+                let entry = match palette.iter().find(|(c, _)| *c == character) {
+                    Some((_, entry)) => entry,
+                    None => {
+                        return Err(PrefabError2D::UnknownCharacter {
+                            character,
+                            row,
+                            column,
+                        })
+                    }
+                };
+                let i = y * width + column;
+                cells[i] = match entry {
+                    PrefabPaletteEntry2D::Transparent => None,
+                    PrefabPaletteEntry2D::Texel(id) => Some(*id),
+                    PrefabPaletteEntry2D::Marker(marker) => {
+                        markers.push(PrefabMarker2D {
+                            name: marker.clone(),
+                            position: Vector2I::new(column as i32, y as i32),
+                        });
+                        Some(Texel2D::EMPTY)
+                    }
+                };
+            }
+        }
+
+        Ok(Prefab2D {
+            name: name.into(),
+            size: Vector2I::new(width as i32, height as i32),
+            cells,
+            markers,
+        })
+    }
+
+    pub fn get_cell(&self, local: &Vector2I) -> Option<TexelID> {
+        if local.x < 0 || local.y < 0 || local.x >= self.size.x || local.y >= self.size.y {
+            return None;
+        }
+        self.cells[(local.y * self.size.x + local.x) as usize]
+    }
+
+    /// Size of the prefab after the transform
+    pub fn transformed_size(&self, transform: &PrefabTransform2D) -> Vector2I {
+        match transform.rotation {
+            PrefabRotation2D::None | PrefabRotation2D::Half => self.size,
+            PrefabRotation2D::Quarter | PrefabRotation2D::ThreeQuarters => {
+                Vector2I::new(self.size.y, self.size.x)
+            }
+        }
+    }
+
+    /// Position of a prefab cell after the transform, relative to the bottom-left corner of the transformed prefab
+    pub fn transform_local(&self, local: &Vector2I, transform: &PrefabTransform2D) -> Vector2I {
+        let (w, h) = (self.size.x, self.size.y);
+        let x = if transform.mirror_x {
+            w - 1 - local.x
+        } else {
+            local.x
+        };
+        let y = local.y;
+        match transform.rotation {
+            PrefabRotation2D::None => Vector2I::new(x, y),
+            PrefabRotation2D::Quarter => Vector2I::new(h - 1 - y, x),
+            PrefabRotation2D::Half => Vector2I::new(w - 1 - x, h - 1 - y),
+            PrefabRotation2D::ThreeQuarters => Vector2I::new(y, w - 1 - x),
+        }
+    }
+
+    /// Global area covered by the prefab when stamped at the position
+    pub fn bounds(&self, position: &Vector2I, transform: &PrefabTransform2D) -> Rect2I {
+        Rect2I::new(
+            *position,
+            *position + self.transformed_size(transform) - Vector2I::ONE,
+        )
+    }
+}
+
+impl Terrain2D {
+    /// Stamp the prefab with its bottom-left corner at the given position.
+    ///
+    /// Returns the prefab's markers in global coordinates.
+    pub fn stamp(
+        &mut self,
+        prefab: &Prefab2D,
+        position: &Vector2I,
+        transform: PrefabTransform2D,
+        mode: StampMode2D,
+    ) -> Vec<PrefabMarker2D> {
+        for (global, id) in self.stamp_texels(prefab, position, transform, mode) {
+            self.set_texel(&global, Texel2D { id, ..default() }, None);
+        }
+
+        prefab
+            .markers
+            .iter()
+            .map(|marker| PrefabMarker2D {
+                name: marker.name.clone(),
+                position: *position + prefab.transform_local(&marker.position, &transform),
+            })
+            .collect()
+    }
+
+    /// Texels that `stamp` would set, without changing the terrain
+    pub fn stamp_texels(
+        &self,
+        prefab: &Prefab2D,
+        position: &Vector2I,
+        transform: PrefabTransform2D,
+        mode: StampMode2D,
+    ) -> Vec<(Vector2I, TexelID)> {
+        let mut texels = vec![];
+        for y in 0..prefab.size.y {
+            for x in 0..prefab.size.x {
+                let local = Vector2I::new(x, y);
+                let global = *position + prefab.transform_local(&local, &transform);
+                let id = match (prefab.get_cell(&local), mode) {
+                    (Some(id), StampMode2D::FillEmpty) => {
+                        if !TexelBehaviour2D::is_empty(
+                            &self.get_texel(&global).unwrap_or_default().id,
+                        ) {
+                            continue;
+                        }
+                        id
+                    }
+                    (Some(id), _) => id,
+                    (None, StampMode2D::Overwrite) => Texel2D::EMPTY,
+                    (None, _) => continue,
+                };
+                texels.push((global, id));
+            }
+        }
+        texels
+    }
+}
+
+/// Where a prefab is allowed to be placed by the world generator
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrefabPlacement2D {
+    Anywhere,
+    /// Most of the covered area must be solid, e.g. vaults hidden inside rock
+    Buried {
+        min_solid_share: f32,
+    },
+    /// Most of the covered area must be open and the row below the prefab must be solid, e.g. ruins on a cave floor
+    OnFloor {
+        min_open_share: f32,
+    },
+}
+
+/// Rule for placing a prefab during world generation
+#[derive(Clone, Debug)]
+pub struct PrefabRule2D {
+    pub prefab: Prefab2D,
+    /// Number of random positions that are tried
+    pub attempts: u32,
+    /// Maximum number of placed prefabs
+    pub max_count: u32,
+    /// Vertical range (inclusive) for the bottom edge of the prefab
+    pub min_y: i32,
+    pub max_y: i32,
+    pub placement: PrefabPlacement2D,
+    pub mode: StampMode2D,
+    /// Allow random rotations and mirroring
+    pub random_transform: bool,
+}
+
+impl TerrainGen2D {
+    /// Place prefabs into already generated terrain by the given rules. Placed prefabs never overlap each other, and
+    /// never cut off open space that was reachable from the cave entrance, so the caves stay connected.
+    ///
+    /// Returns the markers of all placed prefabs in global coordinates.
+    pub fn place_prefabs(
+        &self,
+        terrain: &mut Terrain2D,
+        region: Rect2I,
+        rules: &[PrefabRule2D],
+    ) -> Vec<PrefabMarker2D> {
+        let mut random = Random::new(self.seed as u64 ^ 0x5052_4546_4142);
+        let mut placed: Vec<Rect2I> = vec![];
+        let mut markers = vec![];
+        let entrance = self.caves().map(|caves| caves.entrance);
+        // Open texels reachable from the entrance, updated after each placed prefab
+        let mut reachable =
+            entrance.map(|entrance| reachable_texels(terrain, &region, &entrance, &HashMap::new()));
+
+        for rule in rules.iter() {
+            let mut count = 0;
+            for _ in 0..rule.attempts {
+                if count >= rule.max_count {
+                    break;
+                }
+
+                let transform = if rule.random_transform {
+                    PrefabTransform2D {
+                        rotation: match random.range_i32(0, 4) {
+                            0 => PrefabRotation2D::None,
+                            1 => PrefabRotation2D::Quarter,
+                            2 => PrefabRotation2D::Half,
+                            _ => PrefabRotation2D::ThreeQuarters,
+                        },
+                        mirror_x: random.chance(0.5),
+                    }
+                } else {
+                    PrefabTransform2D::default()
+                };
+                let size = rule.prefab.transformed_size(&transform);
+                let position = Vector2I::new(
+                    random.range_i32(region.min.x, region.max.x - size.x + 2),
+                    random.range_i32(
+                        rule.min_y.max(region.min.y),
+                        rule.max_y.min(region.max.y - size.y + 1) + 1,
+                    ),
+                );
+                let bounds = rule.prefab.bounds(&position, &transform);
+
+                if region.intersection(&bounds) != Some(bounds)
+                    || placed
+                        .iter()
+                        .any(|other| other.intersection(&bounds).is_some())
+                    || !placement_fits(terrain, &bounds, &rule.placement)
+                {
+                    continue;
+                }
+
+                if let (Some(entrance), Some(before)) = (entrance, &reachable) {
+                    let stamped: HashMap<Vector2I, TexelID> = terrain
+                        .stamp_texels(&rule.prefab, &position, transform, rule.mode)
+                        .into_iter()
+                        .collect();
+                    // Only closing a reachable texel can cut anything off, and only opening a texel can
+                    // reach more, so the flood is skipped for stamps that do neither
+                    let closes_reachable = stamped.iter().any(|(global, id)| {
+                        TexelBehaviour2D::has_collision(id)
+                            && region.index_of(global).map_or(false, |index| before[index])
+                    });
+                    let opens = stamped
+                        .values()
+                        .any(|id| !TexelBehaviour2D::has_collision(id));
+                    if closes_reachable || opens {
+                        let after = reachable_texels(terrain, &region, &entrance, &stamped);
+                        // Texels covered by the prefab may close, but nothing outside of it may be cut off
+                        let cuts_off = region.points().enumerate().any(|(index, global)| {
+                            before[index] && !after[index] && !bounds.contains(&global)
+                        });
+                        if cuts_off {
+                            continue;
+                        }
+                        reachable = Some(after);
+                    }
+                }
+
+                let mut stamped = terrain
+                    .with_change_cause(TexelChangeCause2D::Generation, |terrain| {
+                        terrain.stamp(&rule.prefab, &position, transform, rule.mode)
+                    });
+                markers.append(&mut stamped);
+                placed.push(bounds);
+                count += 1;
+            }
+        }
+        markers
+    }
+}
+
+/// Texels of the region without collision that are connected to the start, indexed by `Rect2I::index_of`.
+/// The overlay replaces the texels of the terrain, e.g. to test a stamp before making it.
+fn reachable_texels(
+    terrain: &Terrain2D,
+    region: &Rect2I,
+    start: &Vector2I,
+    overlay: &HashMap<Vector2I, TexelID>,
+) -> Vec<bool> {
+    let is_open = |global: &Vector2I| {
+        let id = match overlay.get(global) {
+            Some(id) => *id,
+            None => terrain.get_texel(global).unwrap_or_default().id,
+        };
+        !TexelBehaviour2D::has_collision(&id)
+    };
+    let mut reachable = vec![false; region.area()];
+    let start_index = match region.index_of(start) {
+        Some(index) if is_open(start) => index,
+        _ => return reachable,
+    };
+    reachable[start_index] = true;
+    let mut queue = VecDeque::from([*start]);
+    while let Some(global) = queue.pop_front() {
+        for offset in [
+            Vector2I::UP,
+            Vector2I::RIGHT,
+            Vector2I::DOWN,
+            Vector2I::LEFT,
+        ] {
+            let neighbour = global + offset;
+            match region.index_of(&neighbour) {
+                Some(index) if !reachable[index] && is_open(&neighbour) => {
+                    reachable[index] = true;
+                    queue.push_back(neighbour);
+                }
+                _ => (),
+            }
+        }
+    }
+    reachable
+}
+
+fn placement_fits(terrain: &Terrain2D, bounds: &Rect2I, placement: &PrefabPlacement2D) -> bool {
+    let solid_count = bounds
+        .points()
+        .filter(|global| {
+            terrain
+                .get_texel(global)
+                .map_or(false, |t| t.has_collision())
+        })
+        .count();
+    let solid_share = solid_count as f32 / bounds.area() as f32;
+
+    match placement {
+        PrefabPlacement2D::Anywhere => true,
+        PrefabPlacement2D::Buried { min_solid_share } => solid_share >= *min_solid_share,
+        PrefabPlacement2D::OnFloor { min_open_share } => {
+            let has_floor = (bounds.min.x..=bounds.max.x).all(|x| {
+                terrain
+                    .get_texel(&Vector2I::new(x, bounds.min.y - 1))
+                    .map_or(false, |t| t.has_collision())
+            });
+            has_floor && 1.0 - solid_share >= *min_open_share
+        }
+    }
+}
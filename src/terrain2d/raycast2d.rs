@@ -0,0 +1,468 @@
+use std::collections::HashSet;
+
+use super::*;
+use crate::util::{Rect2I, Vector2I};
+
+/// Selects which texels a cast can hit. Empty texels are never hit.
+///
+/// Texels outside of the terrain boundaries are hit with `TexelBehaviour2D::OUT_OF_BOUNDS` when the filter matches
+/// it, and unloaded chunks count as empty.
+#[derive(Clone, Debug, Default)]
+pub struct TexelFilter2D {
+    /// Only hit texels with collision
+    pub collision_only: bool,
+    /// Only hit texels of these forms, any form when empty
+    pub forms: Vec<TexelForm>,
+    /// Only hit these materials, any material when empty
+    pub ids: Vec<TexelID>,
+    /// Never hit these materials
+    pub exclude_ids: Vec<TexelID>,
+}
+
+impl TexelFilter2D {
+    /// Hits the same texels that the terrain colliders are built from
+    pub fn collision() -> Self {
+        TexelFilter2D {
+            collision_only: true,
+            ..default()
+        }
+    }
+
+    pub fn forms(forms: &[TexelForm]) -> Self {
+        TexelFilter2D {
+            forms: forms.to_vec(),
+            ..default()
+        }
+    }
+
+    pub fn ids(ids: &[TexelID]) -> Self {
+        TexelFilter2D {
+            ids: ids.to_vec(),
+            ..default()
+        }
+    }
+
+    pub fn matches(&self, texel: &Texel2D, behaviour: &TexelBehaviour2D) -> bool {
+        (!self.collision_only || behaviour.has_collision)
+            && (self.forms.is_empty() || self.forms.contains(&behaviour.form))
+            && (self.ids.is_empty() || self.ids.contains(&texel.id))
+            && !self.exclude_ids.contains(&texel.id)
+    }
+}
+
+/// Shape swept through the texel grid by `Terrain2D::shape_cast`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TerrainCastShape2D {
+    Circle(f32),
+    /// Axis-aligned box with the given half extents
+    Box(Vec2),
+}
+
+#[derive(Clone, Debug)]
+pub struct TerrainHit2D {
+    /// Global position of the hit texel
+    pub global: Vector2I,
+    pub texel: Texel2D,
+    pub behaviour: TexelBehaviour2D,
+    /// Position of the ray, or the center of the cast shape, at the moment of the hit
+    pub point: Vec2,
+    /// Surface normal of the hit texel, zero when the cast starts inside a matching texel
+    pub normal: Vec2,
+    pub distance: f32,
+}
+
+impl Terrain2D {
+    /// Find the first texel matching the filter along a ray. Texel `(x, y)` covers the square from `(x, y)` to
+    /// `(x + 1, y + 1)` in world units, the same as the chunk sprites and colliders.
+    ///
+    /// The walk ends once the ray has left the loaded chunks, so long rays stay cheap even on unbounded terrains.
+    /// Returns None if `max_distance` is not finite.
+    pub fn raycast(
+        &self,
+        origin: Vec2,
+        direction: Vec2,
+        max_distance: f32,
+        filter: &TexelFilter2D,
+    ) -> Option<TerrainHit2D> {
+        if !max_distance.is_finite() {
+            return None;
+        }
+        let direction = direction.try_normalize()?;
+        let bounds = self.cast_bounds();
+        let heading = heading(direction);
+        let mut global = Vector2I::new(origin.x.floor() as i32, origin.y.floor() as i32);
+        let mut normal = Vec2::ZERO;
+        let mut distance = 0.0;
+
+        // Grid DDA, distances along the ray to the next vertical and horizontal texel borders
+        let step = Vector2I::new(direction.x.signum() as i32, direction.y.signum() as i32);
+        let delta = direction.abs().recip();
+        let border_distance = |origin: f32, cell: i32, direction: f32, delta: f32| {
+            if direction > 0.0 {
+                (cell as f32 + 1.0 - origin) * delta
+            } else if direction < 0.0 {
+                (origin - cell as f32) * delta
+            } else {
+                f32::INFINITY
+            }
+        };
+        let mut next = Vec2::new(
+            border_distance(origin.x, global.x, direction.x, delta.x),
+            border_distance(origin.y, global.y, direction.y, delta.y),
+        );
+
+        loop {
+            if let Some((texel, behaviour)) = self.cast_target(&global, filter) {
+                return Some(TerrainHit2D {
+                    global,
+                    texel,
+                    behaviour,
+                    point: origin + direction * distance,
+                    normal,
+                    distance,
+                });
+            }
+            if has_left_bounds(bounds, Rect2I::new(global, global), heading) {
+                // Only texels outside of the terrain boundaries are left to hit
+                return match hits_out_of_bounds(filter) {
+                    true => self.boundary_cast(origin, direction, max_distance, filter),
+                    false => None,
+                };
+            }
+
+            if next.x < next.y {
+                distance = next.x;
+                next.x += delta.x;
+                global.x += step.x;
+                normal = Vec2::new(-step.x as f32, 0.0);
+            } else {
+                distance = next.y;
+                next.y += delta.y;
+                global.y += step.y;
+                normal = Vec2::new(0.0, -step.y as f32);
+            }
+            if distance > max_distance {
+                return None;
+            }
+        }
+    }
+
+    pub fn circle_cast(
+        &self,
+        origin: Vec2,
+        radius: f32,
+        direction: Vec2,
+        max_distance: f32,
+        filter: &TexelFilter2D,
+    ) -> Option<TerrainHit2D> {
+        self.shape_cast(
+            origin,
+            TerrainCastShape2D::Circle(radius),
+            direction,
+            max_distance,
+            filter,
+        )
+    }
+
+    pub fn box_cast(
+        &self,
+        origin: Vec2,
+        half_extents: Vec2,
+        direction: Vec2,
+        max_distance: f32,
+        filter: &TexelFilter2D,
+    ) -> Option<TerrainHit2D> {
+        self.shape_cast(
+            origin,
+            TerrainCastShape2D::Box(half_extents),
+            direction,
+            max_distance,
+            filter,
+        )
+    }
+
+    /// Sweep a shape along a ray and find the first texel matching the filter that it touches.
+    /// Returns None if `max_distance` is not finite.
+    pub fn shape_cast(
+        &self,
+        origin: Vec2,
+        shape: TerrainCastShape2D,
+        direction: Vec2,
+        max_distance: f32,
+        filter: &TexelFilter2D,
+    ) -> Option<TerrainHit2D> {
+        if !max_distance.is_finite() {
+            return None;
+        }
+        let direction = direction.try_normalize()?;
+        let bounds = self.cast_bounds();
+        let heading = heading(direction);
+        let hits_out_of_bounds = hits_out_of_bounds(filter);
+        let half_extents = match shape {
+            TerrainCastShape2D::Circle(radius) => Vec2::splat(radius),
+            TerrainCastShape2D::Box(half_extents) => half_extents,
+        };
+
+        // Texels touched up to some distance are all within the bounds of the shape swept up to that distance,
+        // so the ray is walked in steps of one texel until no closer hit is possible
+        let mut visited: HashSet<Vector2I> = HashSet::new();
+        let mut best: Option<(f32, Vec2, Vector2I, Texel2D, TexelBehaviour2D)> = None;
+        let mut start = 0.0;
+        while start <= max_distance && best.as_ref().map_or(true, |best| best.0 >= start) {
+            let end = (start + 1.0).min(max_distance);
+            let (from, to) = (origin + direction * start, origin + direction * end);
+            let min = from.min(to) - half_extents;
+            let max = from.max(to) + half_extents;
+            for y in (min.y.floor() as i32)..=(max.y.floor() as i32) {
+                for x in (min.x.floor() as i32)..=(max.x.floor() as i32) {
+                    let global = Vector2I::new(x, y);
+                    if !visited.insert(global) {
+                        continue;
+                    }
+                    let texel_min = Vec2::new(x as f32, y as f32);
+                    let hit = match shape {
+                        TerrainCastShape2D::Circle(radius) => {
+                            cast_rounded_box(origin, direction, texel_min, radius)
+                        }
+                        TerrainCastShape2D::Box(half_extents) => cast_box(
+                            origin,
+                            direction,
+                            texel_min - half_extents,
+                            texel_min + Vec2::ONE + half_extents,
+                        ),
+                    };
+                    let (distance, normal) = match hit {
+                        Some(hit) if hit.0 <= max_distance => hit,
+                        _ => continue,
+                    };
+                    if best.as_ref().map_or(false, |best| best.0 <= distance) {
+                        continue;
+                    }
+                    if let Some((texel, behaviour)) = self.cast_target(&global, filter) {
+                        best = Some((distance, normal, global, texel, behaviour));
+                    }
+                }
+            }
+            if end >= max_distance {
+                break;
+            }
+            // The swept area keeps moving away from the loaded chunks
+            let area = Rect2I::new(
+                Vector2I::new(min.x.floor() as i32, min.y.floor() as i32),
+                Vector2I::new(max.x.floor() as i32, max.y.floor() as i32),
+            );
+            if !hits_out_of_bounds && has_left_bounds(bounds, area, heading) {
+                break;
+            }
+            start = end;
+        }
+
+        best.map(
+            |(distance, normal, global, texel, behaviour)| TerrainHit2D {
+                global,
+                texel,
+                behaviour,
+                point: origin + direction * distance,
+                normal,
+                distance,
+            },
+        )
+    }
+
+    /// Loaded chunks clipped to the terrain boundaries, the only texels a cast can hit besides the out of bounds
+    /// texels. None if there are no such texels.
+    fn cast_bounds(&self) -> Option<Rect2I> {
+        let mut indices = self.chunk_iter().map(|(index, _)| *index);
+        let first = indices.next()?;
+        let chunks = indices.fold(Rect2I::new(first, first), |chunks, index| {
+            chunks.include_point(index)
+        });
+        let mut bounds = Rect2I::new(
+            chunk_index_to_global(&chunks.min),
+            chunk_index_to_global(&chunks.max) + Chunk2D::SIZE - Vector2I::ONE,
+        );
+        if let Some(top) = self.top_boundary {
+            bounds.max.y = bounds.max.y.min(top - 1);
+        }
+        if let Some(bottom) = self.bottom_boundary {
+            bounds.min.y = bounds.min.y.max(bottom);
+        }
+        if let Some(left) = self.left_boundary {
+            bounds.min.x = bounds.min.x.max(left);
+        }
+        if let Some(right) = self.right_boundary {
+            bounds.max.x = bounds.max.x.min(right - 1);
+        }
+        (bounds.min.x <= bounds.max.x && bounds.min.y <= bounds.max.y).then_some(bounds)
+    }
+
+    /// Hit where the ray first crosses a terrain boundary, or None if it doesn't within `max_distance`
+    fn boundary_cast(
+        &self,
+        origin: Vec2,
+        direction: Vec2,
+        max_distance: f32,
+        filter: &TexelFilter2D,
+    ) -> Option<TerrainHit2D> {
+        // Boundary, axis, direction of the out of bounds side, and offset to the first texel past the boundary
+        let crossings = [
+            (self.top_boundary, 1, 1.0, 0),
+            (self.bottom_boundary, 1, -1.0, -1),
+            (self.right_boundary, 0, 1.0, 0),
+            (self.left_boundary, 0, -1.0, -1),
+        ]
+        .into_iter()
+        .filter_map(|(boundary, axis, side, offset)| {
+            let boundary = boundary?;
+            if direction[axis] * side <= 0.0 {
+                return None;
+            }
+            let distance = ((boundary as f32 - origin[axis]) / direction[axis]).max(0.0);
+            let mut normal = Vec2::ZERO;
+            normal[axis] = -side;
+            Some((distance, axis, boundary + offset, normal))
+        });
+        let (distance, axis, coordinate, normal) = crossings.min_by(|a, b| a.0.total_cmp(&b.0))?;
+        if distance > max_distance {
+            return None;
+        }
+        let point = origin + direction * distance;
+        let mut global = Vector2I::new(point.x.floor() as i32, point.y.floor() as i32);
+        match axis {
+            0 => global.x = coordinate,
+            _ => global.y = coordinate,
+        }
+        let (texel, behaviour) = self.cast_target(&global, filter)?;
+        Some(TerrainHit2D {
+            global,
+            texel,
+            behaviour,
+            point,
+            normal,
+            distance,
+        })
+    }
+
+    /// Texel and behaviour at the position, if a cast with the filter should hit it
+    fn cast_target(
+        &self,
+        global: &Vector2I,
+        filter: &TexelFilter2D,
+    ) -> Option<(Texel2D, TexelBehaviour2D)> {
+        let (texel, behaviour) = self.get_texel_behaviour(global);
+        let texel = texel.unwrap_or_default();
+        behaviour
+            .filter(|behaviour| filter.matches(&texel, behaviour))
+            .map(|behaviour| (texel, behaviour))
+    }
+}
+
+/// Can the filter hit the texels outside of the terrain boundaries
+fn hits_out_of_bounds(filter: &TexelFilter2D) -> bool {
+    filter.matches(&Texel2D::default(), &TexelBehaviour2D::OUT_OF_BOUNDS)
+}
+
+/// Sign of each component of the direction, zero for zero components
+fn heading(direction: Vec2) -> Vector2I {
+    let sign = |value: f32| (value > 0.0) as i32 - (value < 0.0) as i32;
+    Vector2I::new(sign(direction.x), sign(direction.y))
+}
+
+/// Is the area outside of the bounds, and stays outside when moving towards the heading
+fn has_left_bounds(bounds: Option<Rect2I>, area: Rect2I, heading: Vector2I) -> bool {
+    let bounds = match bounds {
+        Some(bounds) => bounds,
+        None => return true,
+    };
+    (area.max.x < bounds.min.x && heading.x <= 0)
+        || (area.min.x > bounds.max.x && heading.x >= 0)
+        || (area.max.y < bounds.min.y && heading.y <= 0)
+        || (area.min.y > bounds.max.y && heading.y >= 0)
+}
+
+/// Distance along the ray to an axis-aligned box and the normal of the entered face.
+/// Zero distance and normal if the ray starts inside the box.
+fn cast_box(origin: Vec2, direction: Vec2, min: Vec2, max: Vec2) -> Option<(f32, Vec2)> {
+    let mut enter = f32::NEG_INFINITY;
+    let mut exit = f32::INFINITY;
+    let mut normal = Vec2::ZERO;
+    for axis in 0..2 {
+        if direction[axis] == 0.0 {
+            if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                return None;
+            }
+            continue;
+        }
+        let (near, far) = if direction[axis] > 0.0 {
+            (min[axis], max[axis])
+        } else {
+            (max[axis], min[axis])
+        };
+        let t_near = (near - origin[axis]) / direction[axis];
+        let t_far = (far - origin[axis]) / direction[axis];
+        if t_near > enter {
+            enter = t_near;
+            normal = Vec2::ZERO;
+            normal[axis] = -direction[axis].signum();
+        }
+        exit = exit.min(t_far);
+    }
+    if enter > exit || exit < 0.0 {
+        None
+    } else if enter <= 0.0 {
+        Some((0.0, Vec2::ZERO))
+    } else {
+        Some((enter, normal))
+    }
+}
+
+/// Distance along the ray to a circle and the normal at the hit point.
+/// Zero distance and normal if the ray starts inside the circle.
+fn cast_circle(origin: Vec2, direction: Vec2, center: Vec2, radius: f32) -> Option<(f32, Vec2)> {
+    let offset = origin - center;
+    let c = offset.length_squared() - radius * radius;
+    if c <= 0.0 {
+        return Some((0.0, Vec2::ZERO));
+    }
+    let b = offset.dot(direction);
+    let discriminant = b * b - c;
+    if b > 0.0 || discriminant < 0.0 {
+        return None;
+    }
+    let distance = -b - discriminant.sqrt();
+    let normal = (origin + direction * distance - center).normalize_or_zero();
+    Some((distance, normal))
+}
+
+/// Cast against a texel grown by the radius of a circle, i.e. a box with rounded corners
+fn cast_rounded_box(
+    origin: Vec2,
+    direction: Vec2,
+    texel_min: Vec2,
+    radius: f32,
+) -> Option<(f32, Vec2)> {
+    let texel_max = texel_min + Vec2::ONE;
+    let wide = Vec2::new(radius, 0.0);
+    let tall = Vec2::new(0.0, radius);
+    [
+        cast_box(origin, direction, texel_min - wide, texel_max + wide),
+        cast_box(origin, direction, texel_min - tall, texel_max + tall),
+        cast_circle(origin, direction, texel_min, radius),
+        cast_circle(
+            origin,
+            direction,
+            Vec2::new(texel_max.x, texel_min.y),
+            radius,
+        ),
+        cast_circle(
+            origin,
+            direction,
+            Vec2::new(texel_min.x, texel_max.y),
+            radius,
+        ),
+        cast_circle(origin, direction, texel_max, radius),
+    ]
+    .into_iter()
+    .flatten()
+    .min_by(|a, b| a.0.total_cmp(&b.0))
+}
@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::*;
+use crate::util::{raster, Rect2I, Vector2I};
+
+/// Area of the terrain. A texel belongs to a circle or polygon when its center is inside it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TerrainRegion2D {
+    Rect(Rect2I),
+    Circle {
+        center: Vec2,
+        radius: f32,
+    },
+    /// Points of a simple polygon in either winding order, without repeating the first point
+    Polygon(Vec<Vec2>),
+}
+
+impl TerrainRegion2D {
+    /// Smallest rectangle containing every texel of the region
+    pub fn bounds(&self) -> Option<Rect2I> {
+        let (min, max) = match self {
+            TerrainRegion2D::Rect(rect) => return Some(*rect),
+            TerrainRegion2D::Circle { center, radius } => (
+                *center - Vec2::splat(*radius),
+                *center + Vec2::splat(*radius),
+            ),
+            TerrainRegion2D::Polygon(points) => return raster::polygon_bounds(points),
+        };
+        // Texels whose centers can be within the area
+        Some(Rect2I::new(
+            Vector2I::new((min.x - 0.5).ceil() as i32, (min.y - 0.5).ceil() as i32),
+            Vector2I::new((max.x - 0.5).floor() as i32, (max.y - 0.5).floor() as i32),
+        ))
+    }
+
+    pub fn contains(&self, global: &Vector2I) -> bool {
+        let center = Vec2::new(global.x as f32 + 0.5, global.y as f32 + 0.5);
+        match self {
+            TerrainRegion2D::Rect(rect) => rect.contains(global),
+            TerrainRegion2D::Circle {
+                center: circle_center,
+                radius,
+            } => center.distance_squared(*circle_center) <= radius * radius,
+            TerrainRegion2D::Polygon(points) => raster::polygon_contains(points, global),
+        }
+    }
+
+    /// Texels of the region
+    pub fn points(&self) -> impl Iterator<Item = Vector2I> + '_ {
+        self.bounds()
+            .into_iter()
+            .flat_map(|bounds| bounds.points())
+            .filter(|global| self.contains(global))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MaterialCount2D {
+    pub count: u32,
+    /// Sum of texel densities, i.e. the mass of gases
+    pub total_density: u32,
+}
+
+/// Texel counts per material. Empty texels are counted with `Texel2D::EMPTY`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MaterialStats2D {
+    materials: HashMap<TexelID, MaterialCount2D>,
+}
+
+impl MaterialStats2D {
+    pub fn add(&mut self, texel: &Texel2D) {
+        let material = self.materials.entry(texel.id).or_default();
+        material.count += 1;
+        material.total_density += texel.density as u32;
+    }
+
+    pub fn get(&self, id: &TexelID) -> MaterialCount2D {
+        self.materials.get(id).copied().unwrap_or_default()
+    }
+
+    pub fn count(&self, id: &TexelID) -> u32 {
+        self.get(id).count
+    }
+
+    /// Number of counted texels, including empty ones
+    pub fn total(&self) -> u32 {
+        self.materials.values().map(|material| material.count).sum()
+    }
+
+    /// Share of the counted texels that are of the material, between 0 and 1
+    pub fn ratio(&self, id: &TexelID) -> f32 {
+        match self.total() {
+            0 => 0.0,
+            total => self.count(id) as f32 / total as f32,
+        }
+    }
+
+    /// Counts sorted by ID
+    pub fn sorted(&self) -> Vec<(TexelID, MaterialCount2D)> {
+        let mut materials: Vec<_> = self
+            .materials
+            .iter()
+            .map(|(id, material)| (*id, *material))
+            .collect();
+        materials.sort_unstable_by_key(|(id, _)| *id);
+        materials
+    }
+}
+
+/// Result of `Terrain2D::flood_fill`
+#[derive(Clone, Debug, Default)]
+pub struct FloodFill2D {
+    pub texels: Vec<Vector2I>,
+    pub stats: MaterialStats2D,
+    /// The fill stopped at the size limit, so the connected region is larger than `texels`
+    pub truncated: bool,
+}
+
+impl Terrain2D {
+    /// Count the materials of the loaded texels within the region
+    pub fn material_stats(&self, region: &TerrainRegion2D) -> MaterialStats2D {
+        let mut stats = MaterialStats2D::default();
+        for global in region.points() {
+            if let Some(texel) = self.get_texel(&global) {
+                stats.add(&texel);
+            }
+        }
+        stats
+    }
+
+    /// Total density of every gas in the loaded chunks
+    pub fn gas_mass(&self) -> HashMap<TexelID, u64> {
+        let mut mass: HashMap<TexelID, u64> = HashMap::new();
+        for (_, chunk) in self.chunk_iter() {
+            for texel in chunk.texels.iter() {
+                if TexelBehaviour2D::from_id(&texel.id).map_or(false, |b| b.form == TexelForm::Gas)
+                {
+                    *mass.entry(texel.id).or_insert(0) += texel.density as u64;
+                }
+            }
+        }
+        mass
+    }
+
+    /// Collect the texels connected to `start` through the given materials, without going through diagonals.
+    /// Include `Texel2D::EMPTY` to fill e.g. the air and water of a room. Unloaded chunks and texels outside of the
+    /// boundaries stop the fill. At most `max_size` texels are collected.
+    pub fn flood_fill(&self, start: &Vector2I, ids: &[TexelID], max_size: usize) -> FloodFill2D {
+        let mut result = FloodFill2D::default();
+        let fillable_texel = |global: &Vector2I| {
+            if !self.is_within_boundaries(global) {
+                return None;
+            }
+            self.get_texel(global)
+                .filter(|texel| ids.contains(&texel.id))
+        };
+
+        let mut visited: HashSet<Vector2I> = HashSet::from([*start]);
+        let mut queue: VecDeque<Vector2I> = VecDeque::from([*start]);
+        while let Some(global) = queue.pop_front() {
+            let texel = match fillable_texel(&global) {
+                Some(texel) => texel,
+                None => continue,
+            };
+            if result.texels.len() >= max_size {
+                result.truncated = true;
+                break;
+            }
+            result.texels.push(global);
+            result.stats.add(&texel);
+            for offset in Chunk2D::NEIGHBOUR_OFFSET_VECTORS.iter() {
+                let neighbour = global + *offset;
+                if visited.insert(neighbour) {
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,513 @@
+use std::time::Instant;
+
+use super::*;
+use crate::util::{Random, Vector2I};
+
+/// Seeded random number generator of the terrain simulation.
+///
+/// Every random choice of `Terrain2D::step` is drawn from it, so runs with the same seed and inputs stay identical.
+#[derive(Resource, Clone, Debug)]
+pub struct SimulationRandom2D {
+    pub seed: u64,
+    pub random: Random,
+}
+
+impl Default for SimulationRandom2D {
+    fn default() -> Self {
+        SimulationRandom2D::new(0)
+    }
+}
+
+impl SimulationRandom2D {
+    pub fn new(seed: u64) -> SimulationRandom2D {
+        SimulationRandom2D {
+            seed,
+            random: Random::new(seed),
+        }
+    }
+
+    /// Restart the sequence from a new seed
+    pub fn reseed(&mut self, seed: u64) {
+        *self = SimulationRandom2D::new(seed);
+    }
+}
+
+/// Work done by `Terrain2D::step`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TerrainStepStats2D {
+    /// Chunks that had a dirty rect to simulate
+    pub active_chunks: u32,
+    pub simulated_texels: u32,
+    /// Time spent on gas dispersion in milliseconds
+    pub gas_dispersion_ms: f32,
+}
+
+impl Terrain2D {
+    /// Run one tick of the texel simulation on the dirty rects of the loaded chunks.
+    ///
+    /// The tick should increase by one every step, `terrain_simulation` passes `FrameCounter::frame`. Random choices
+    /// are drawn from `random`, and chunks are simulated in a fixed order, so the result only depends on the state,
+    /// the tick and the random state.
+    pub fn step(&mut self, tick: u64, random: &mut Random) -> TerrainStepStats2D {
+        let mut stats = TerrainStepStats2D::default();
+        let previous_cause = self.set_change_cause(TexelChangeCause2D::Simulation);
+        let simulation_frame = (tick % u8::MAX as u64) as u8 + 1;
+
+        for chunk_index in self.sorted_chunk_indices().iter() {
+            // Mark few chunks dirty in interval. Should help activate stale chunks
+            if let Some(chunk) = self.index_to_chunk_mut(&chunk_index) {
+                let interval = 1;
+                if tick % interval == 0 {
+                    let i = ((tick / interval) % 100) as i32;
+                    if (chunk_index.y % 10) * 10 + (chunk_index.x % 10) == i {
+                        chunk.mark_all_dirty();
+                    }
+                }
+            };
+
+            if let Some(rect) = &self
+                .index_to_chunk(&chunk_index)
+                .map_or(None, |chunk| chunk.dirty_rect.clone())
+            {
+                if let Some(chunk) = self.index_to_chunk_mut(&chunk_index) {
+                    chunk.mark_clean();
+                } else {
+                    continue;
+                };
+                stats.active_chunks += 1;
+
+                // Texel simulation
+                let mut y_range: Vec<_> = (rect.min.y..rect.max.y + 1).collect();
+                let mut x_range: Vec<_> = (rect.min.x..rect.max.x + 1).collect();
+                if random.chance(0.5) {
+                    y_range.reverse();
+                }
+                if random.chance(0.5) {
+                    x_range.reverse();
+                }
+
+                for y in y_range.iter() {
+                    for x in x_range.iter() {
+                        let local = Vector2I::new(*x, *y);
+                        let global = local_to_global(&local, &chunk_index);
+
+                        if self
+                            .get_latest_simulation(&global)
+                            .map_or(true, |frame| frame == simulation_frame)
+                        {
+                            continue;
+                        };
+
+                        simulate_texel(global, self, simulation_frame, random);
+                        stats.simulated_texels += 1;
+                    }
+                }
+
+                // Gas dispersion
+                let gas_start = Instant::now();
+                // The blocks alternate between two offsets so that gas can cross block borders
+                let alternate_dispersion = tick % 2 == 0;
+                let alternate = if alternate_dispersion { 1 } else { 0 };
+                let y_range =
+                    ((rect.min.y - alternate)..rect.max.y + 1 + alternate).collect::<Vec<_>>();
+                let x_range =
+                    ((rect.min.x - alternate)..rect.max.x + 1 + alternate).collect::<Vec<_>>();
+                const DISPERSION_WIDTH: usize = 2;
+                const DISPERSION_HEIGHT: usize = 2;
+                for y_arr in y_range.chunks(DISPERSION_HEIGHT) {
+                    for x_arr in x_range.chunks(DISPERSION_WIDTH) {
+                        let mut global_positions = vec![];
+                        for y in y_arr.iter() {
+                            for x in x_arr.iter() {
+                                let local = Vector2I::new(*x, *y);
+                                let global = local_to_global(&local, &chunk_index);
+                                global_positions.push(global);
+                            }
+                        }
+
+                        // Distribute gas
+                        disperse_gas(global_positions, self)
+                    }
+                }
+                stats.gas_dispersion_ms += elapsed_ms(gas_start);
+            }
+        }
+
+        self.set_change_cause(previous_cause);
+        stats
+    }
+
+    /// Hash of the texels, simulation frames and dirty rects of the loaded chunks, for checking that two runs stay
+    /// identical tick by tick.
+    ///
+    /// The hash is 64-bit FNV-1a over the chunks in `sorted_chunk_indices` order. Each chunk adds its index as two
+    /// little-endian i32s, the ID and density of every texel, the simulation frames, and a zero byte if it is clean
+    /// or a one byte followed by the dirty rect corners as little-endian i32s. It doesn't depend on the platform or
+    /// Rust version, so hashes from different machines can be compared.
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = Fnv1a::new();
+        for index in self.sorted_chunk_indices().iter() {
+            let chunk = match self.index_to_chunk(index) {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            hash.write_i32(index.x);
+            hash.write_i32(index.y);
+            for texel in chunk.texels.iter() {
+                hash.write(&[texel.id, texel.density]);
+            }
+            hash.write(&chunk.simulation_frames);
+            match &chunk.dirty_rect {
+                Some(rect) => {
+                    hash.write(&[1]);
+                    for value in [rect.min.x, rect.min.y, rect.max.x, rect.max.y] {
+                        hash.write_i32(value);
+                    }
+                }
+                None => hash.write(&[0]),
+            }
+        }
+        hash.finish()
+    }
+
+    /// Chunk indices row by row from the bottom, since the iteration order of the chunk map varies between runs
+    fn sorted_chunk_indices(&self) -> Vec<Chunk2DIndex> {
+        let mut indices: Vec<Chunk2DIndex> = self.chunk_iter().map(|(index, _)| *index).collect();
+        indices.sort_unstable_by_key(|index| (index.y, index.x));
+        indices
+    }
+}
+
+/// 64-bit FNV-1a, see `Terrain2D::state_hash`
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    fn new() -> Fnv1a {
+        Fnv1a(Self::OFFSET_BASIS)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn write_i32(&mut self, value: i32) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn disperse_gas(global_positions: Vec<Vector2I>, terrain: &mut Terrain2D) {
+    use u32 as Capacity;
+    let mut total_densities: HashMap<TexelID, Capacity> = HashMap::new();
+    // let mut total_densities: Vec<(TexelID, Capacity)> = vec![];
+    let mut valid_globals = vec![];
+    for global in global_positions.iter() {
+        let (texel, behaviour) = terrain.get_texel_behaviour(global);
+        if behaviour.clone().map_or(true, |b| b.form == TexelForm::Gas) {
+            valid_globals.push(*global);
+        }
+        match (texel, behaviour) {
+            (Some(texel), Some(behaviour)) => {
+                if behaviour.form == TexelForm::Gas {
+                    total_densities.insert(
+                        texel.id,
+                        texel.density as u32
+                            + total_densities.get(&texel.id).map_or(0, |density| *density),
+                    );
+                }
+            }
+            (_, _) => (),
+        }
+    }
+
+    let mut total_densities: Vec<(TexelID, Capacity)> =
+        total_densities.iter().map(|(t, d)| (*t, *d)).collect();
+
+    if total_densities.len() == 0 {
+        return;
+    }
+
+    // The ID breaks ties, since the order of the map varies between runs
+    total_densities.sort_unstable_by_key(|(id, density)| (*density, *id));
+    total_densities.reverse();
+
+    const TILE_CAPACITY: u32 = u8::MAX as u32;
+    let free_slots = valid_globals.len() as u32
+        - total_densities
+            .iter()
+            .map(|(_, v)| (*v / (TILE_CAPACITY + 1)) + 1)
+            .sum::<u32>();
+
+    // Allocate slots
+    let mut slots: Vec<(TexelID, u32)> = vec![];
+    for (id, density) in total_densities.iter() {
+        let min_slots = (density / (TILE_CAPACITY + 1)) + 1;
+        slots.push((*id, min_slots));
+    }
+    for i in 0..free_slots as usize {
+        let len = slots.len();
+        slots[i % len].1 += 1;
+    }
+
+    // Disperse into given slots
+    let mut texels: Vec<Texel2D> = vec![];
+    for (id, total_density) in total_densities.iter() {
+        let slots = slots.iter().find(|s| s.0 == *id).unwrap().1;
+        let mut density_left = *total_density;
+        for i in 0..slots {
+            let density = if i < (slots - 1) {
+                (total_density / slots).min(density_left)
+            } else {
+                density_left
+            }
+            .min(u8::MAX as u32);
+            if density > 0 {
+                texels.push(Texel2D {
+                    id: *id,
+                    density: density as u8,
+                });
+                density_left -= density;
+            }
+        }
+    }
+
+    // Apply changes
+    if texels.len() > valid_globals.len() {
+        panic!("disperse_gas() - valid_globals is shorter than texels");
+    }
+
+    for i in 0..valid_globals.len() {
+        let global = valid_globals[i];
+        if i < texels.len() {
+            let texel = texels[i];
+            terrain.set_texel(&global, texel, None);
+        } else {
+            terrain.set_texel(&global, Texel2D::default(), None)
+        }
+    }
+}
+
+fn simulate_texel(
+    global: Vector2I,
+    terrain: &mut Terrain2D,
+    simulation_frame: u8,
+    random: &mut Random,
+) {
+    let (texel, behaviour) = match terrain.get_texel_behaviour(&global) {
+        (Some(texel), Some(behaviour)) => (texel, behaviour),
+        (_, _) => return,
+    };
+
+    // Spreading
+    if let Some(spread) = behaviour.spread {
+        spread_texel(global, texel, spread, terrain, simulation_frame, random);
+    }
+
+    // Gravity
+    if let Some(gravity) = behaviour.gravity {
+        let grav_offset = Vector2I::from(gravity);
+        let grav_pos = global + grav_offset;
+
+        // Try falling
+        {
+            let (_, other_behaviour) = terrain.get_texel_behaviour(&grav_pos);
+            if TexelBehaviour2D::can_displace(&behaviour, &other_behaviour) {
+                terrain.swap_texels(&global, &grav_pos, Some(simulation_frame));
+                return;
+            }
+            if terrain.can_transfer_density(&global, &grav_pos) {
+                terrain.transfer_density(&global, &grav_pos, gravity, Some(simulation_frame))
+            }
+        }
+
+        // Try "sliding"
+        let mut dirs = vec![Vector2I::RIGHT, Vector2I::LEFT];
+        if random.chance(0.5) {
+            dirs.reverse();
+        }
+        for dir in dirs.iter() {
+            let slide_pos = match behaviour.form {
+                TexelForm::Solid => grav_pos + *dir,
+                TexelForm::Liquid | TexelForm::Gas => global + *dir,
+            };
+            let (_, other_behaviour) = terrain.get_texel_behaviour(&slide_pos);
+            if TexelBehaviour2D::can_displace(&behaviour, &other_behaviour) {
+                terrain.swap_texels(&global, &slide_pos, Some(simulation_frame));
+                return;
+            }
+            if terrain.can_transfer_density(&global, &grav_pos) {
+                terrain.transfer_density(&global, &grav_pos, gravity, Some(simulation_frame))
+            }
+        }
+
+        // The texel is at rest. Once the latest move is old enough to count as settled, forget it so that it
+        // can't be mistaken for a recent one when the simulation frames wrap around.
+        if let Some(latest) = terrain.get_latest_simulation(&global) {
+            if latest != 0 && simulation_frames_since(simulation_frame, latest) > u8::MAX / 2 {
+                terrain.clear_latest_simulation(&global);
+            }
+        }
+    }
+}
+
+fn spread_texel(
+    global: Vector2I,
+    texel: Texel2D,
+    spread: TexelSpread2D,
+    terrain: &mut Terrain2D,
+    simulation_frame: u8,
+    random: &mut Random,
+) {
+    let is_exposed = |terrain: &Terrain2D, global: &Vector2I| {
+        TexelBehaviour2D::is_open(&terrain.get_texel_behaviour(&(*global + Vector2I::UP)).1)
+    };
+
+    // Covered texels turn back into the material they grew on
+    if !is_exposed(terrain, &global) {
+        terrain.set_texel(
+            &global,
+            Texel2D {
+                id: spread.target,
+                ..default()
+            },
+            Some(simulation_frame),
+        );
+        return;
+    }
+
+    let mut waiting = false;
+    for y in -1..=1 {
+        for x in -1..=1 {
+            let target = global + Vector2I { x, y };
+            if target == global
+                || terrain
+                    .get_texel(&target)
+                    .map_or(true, |t| t.id != spread.target)
+                || !is_exposed(terrain, &target)
+            {
+                continue;
+            }
+            if random.chance(spread.chance as f64) {
+                terrain.set_texel(
+                    &target,
+                    Texel2D {
+                        id: texel.id,
+                        ..default()
+                    },
+                    Some(simulation_frame),
+                );
+            } else {
+                waiting = true;
+            }
+        }
+    }
+
+    // Keep the chunk active until there is nothing left to spread to
+    if waiting {
+        terrain.mark_dirty(&global);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOOSE_SAND: TexelID = 1;
+    const WATER: TexelID = 4;
+    const LIGHT_GAS: TexelID = 6;
+    const STONE: TexelID = 12;
+
+    fn texel(id: TexelID) -> Texel2D {
+        Texel2D { id, ..default() }
+    }
+
+    #[test]
+    fn sand_falls_one_texel_per_tick_and_rests_on_the_floor() {
+        let mut terrain = Terrain2D::new(None, None, None, None);
+        for x in 0..8 {
+            terrain.set_texel(&Vector2I::new(x, 0), texel(STONE), None);
+        }
+        let start = Vector2I::new(4, 10);
+        terrain.set_texel(&start, texel(LOOSE_SAND), None);
+
+        let mut random = Random::new(47);
+        for tick in 0..20 {
+            terrain.step(tick, &mut random);
+            let expected = Vector2I::new(4, (start.y - tick as i32 - 1).max(1));
+            for y in 0..=start.y {
+                let global = Vector2I::new(4, y);
+                let id = terrain.get_texel(&global).unwrap_or_default().id;
+                match global == expected {
+                    true => assert_eq!(id, LOOSE_SAND, "tick {tick}: no sand at {global:?}"),
+                    false if y == 0 => assert_eq!(id, STONE, "tick {tick}: floor changed"),
+                    false => {
+                        assert_eq!(id, Texel2D::EMPTY, "tick {tick}: texel left at {global:?}")
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fnv1a_matches_reference_values() {
+        let hash = |bytes: &[u8]| {
+            let mut hash = Fnv1a::new();
+            hash.write(bytes);
+            hash.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    /// Terrain with a floor, walls and a mix of falling, flowing and rising materials
+    fn busy_terrain() -> Terrain2D {
+        let mut terrain = Terrain2D::new(None, None, None, None);
+        let mut random = Random::new(48);
+        for y in 0..40 {
+            for x in 0..40 {
+                let id = if y == 0 || x == 0 || x == 39 {
+                    STONE
+                } else if random.chance(0.2) {
+                    [LOOSE_SAND, WATER, LIGHT_GAS][random.range_i32(0, 3) as usize]
+                } else {
+                    continue;
+                };
+                terrain.set_texel(&Vector2I::new(x, y), texel(id), None);
+            }
+        }
+        terrain
+    }
+
+    fn hashes(seed: u64, ticks: u64) -> Vec<u64> {
+        let mut terrain = busy_terrain();
+        let mut random = SimulationRandom2D::new(seed);
+        (0..ticks)
+            .map(|tick| {
+                terrain.step(tick, &mut random.random);
+                terrain.state_hash()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_same_hashes() {
+        let first = hashes(1, 50);
+        let second = hashes(1, 50);
+        for (tick, (a, b)) in first.iter().zip(second.iter()).enumerate() {
+            assert_eq!(a, b, "tick {tick}");
+        }
+
+        // The random choices are used, so another seed ends up elsewhere
+        let other = hashes(2, 50);
+        assert_ne!(first.last(), other.last());
+    }
+}
@@ -0,0 +1,131 @@
+use super::*;
+use crate::util::{raster, Rect2I, Vector2I};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EditShape2D {
+    Point(Vector2I),
+    /// Arbitrary texels, e.g. the result of a flood fill
+    Points(Vec<Vector2I>),
+    Line {
+        from: Vector2I,
+        to: Vector2I,
+        width: f32,
+    },
+    Circle {
+        center: Vector2I,
+        radius: f32,
+    },
+    /// Points of the polygon in world space, without repeating the first point
+    Polygon(Vec<Vec2>),
+    Rect(Rect2I),
+}
+
+impl EditShape2D {
+    /// Texels covered by the shape, see `util::raster`
+    pub fn points(&self) -> Vec<Vector2I> {
+        match self {
+            EditShape2D::Point(point) => vec![*point],
+            EditShape2D::Points(points) => points.clone(),
+            EditShape2D::Line { from, to, width } => raster::thick_line(*from, *to, *width),
+            EditShape2D::Circle { center, radius } => raster::filled_circle(*center, *radius),
+            EditShape2D::Polygon(points) => raster::filled_polygon(points),
+            EditShape2D::Rect(rect) => raster::filled_rect(rect),
+        }
+    }
+}
+
+/// Which texels of the shape an edit replaces
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EditMode2D {
+    #[default]
+    Replace,
+    /// Only fill empty texels
+    OnlyEmpty,
+    /// Only replace texels of the material, e.g. to turn dirt into stone without touching anything else
+    OnlyMaterial(TexelID),
+}
+
+impl EditMode2D {
+    pub fn allows(&self, texel: &Texel2D) -> bool {
+        match self {
+            EditMode2D::Replace => true,
+            EditMode2D::OnlyEmpty => texel.id == Texel2D::EMPTY,
+            EditMode2D::OnlyMaterial(id) => texel.id == *id,
+        }
+    }
+}
+
+/// Shape filled with a texel. Applied with `Terrain2D::apply_edit` as a single batch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TerrainEdit2D {
+    pub shape: EditShape2D,
+    pub texel: Texel2D,
+    pub mode: EditMode2D,
+}
+
+impl TerrainEdit2D {
+    pub fn new(shape: EditShape2D, id: TexelID) -> Self {
+        TerrainEdit2D {
+            shape,
+            texel: Texel2D { id, ..default() },
+            mode: EditMode2D::default(),
+        }
+    }
+
+    /// Edit that empties the texels of the shape
+    pub fn erase(shape: EditShape2D) -> Self {
+        TerrainEdit2D::new(shape, Texel2D::EMPTY)
+    }
+
+    pub fn with_mode(self, mode: EditMode2D) -> Self {
+        TerrainEdit2D { mode, ..self }
+    }
+}
+
+/// Texels copied from a rectangle of the terrain
+#[derive(Clone, Debug)]
+pub struct TerrainClipboard2D {
+    pub size: Vector2I,
+    /// Row-major from the bottom-left corner. None for texels that weren't loaded.
+    pub texels: Vec<Option<Texel2D>>,
+}
+
+impl TerrainClipboard2D {
+    /// Texels to paste with the bottom-left corner at the position, see `Terrain2D::set_texels`
+    pub fn texels_at(&self, position: Vector2I) -> impl Iterator<Item = (Vector2I, Texel2D)> + '_ {
+        let rect = Rect2I::new(Vector2I::ZERO, self.size - Vector2I::ONE);
+        self.texels
+            .iter()
+            .enumerate()
+            .filter_map(move |(i, texel)| texel.map(|texel| (position + rect.point_at(i), texel)))
+    }
+}
+
+impl Terrain2D {
+    pub fn copy_region(&self, rect: &Rect2I) -> TerrainClipboard2D {
+        TerrainClipboard2D {
+            size: rect.size(),
+            texels: rect
+                .points()
+                .map(|global| self.get_texel(&global))
+                .collect(),
+        }
+    }
+
+    /// Apply the edit through `set_texels`. Unloaded texels count as empty. Returns the texels that changed.
+    pub fn apply_edit(&mut self, edit: &TerrainEdit2D) -> Vec<TexelChange2D> {
+        let texels: Vec<(Vector2I, Texel2D)> = edit
+            .shape
+            .points()
+            .into_iter()
+            .filter(|global| {
+                edit.mode
+                    .allows(&self.get_texel(global).unwrap_or_default())
+            })
+            .map(|global| (global, edit.texel))
+            .collect();
+        self.with_change_cause(TexelChangeCause2D::Edit, |terrain| {
+            terrain.set_texels(texels)
+        })
+    }
+}
@@ -1,10 +1,66 @@
 use noise::{NoiseFn, PerlinSurflet};
 
 use super::*;
+use crate::util::Rect2I;
+
+/// Height-mapped surface with open sky above it
+#[derive(Clone, Debug)]
+pub struct SurfaceSettings2D {
+    /// Average height of the surface
+    pub height: i32,
+    /// Maximum distance of the surface from the average height
+    pub amplitude: i32,
+    pub frequency: f64,
+    /// Depth of the dirt layer below the surface
+    pub dirt_depth: i32,
+    /// Cover the exposed top of the dirt layer with grass
+    pub grass: bool,
+}
+
+impl Default for SurfaceSettings2D {
+    fn default() -> Self {
+        SurfaceSettings2D {
+            height: 420,
+            amplitude: 40,
+            frequency: 1.0 / 160.0,
+            dirt_depth: 12,
+            grass: true,
+        }
+    }
+}
+
+impl SurfaceSettings2D {
+    pub fn max_height(&self) -> i32 {
+        self.height + self.amplitude
+    }
+}
+
+/// Indestructible layer at the bottom of the world
+#[derive(Clone, Debug)]
+pub struct BedrockSettings2D {
+    /// Lowest row of the bedrock, usually the bottom boundary of the terrain
+    pub bottom: i32,
+    pub thickness: i32,
+    /// Maximum amount of additional rows on top of the thickness
+    pub variation: i32,
+}
+
+impl Default for BedrockSettings2D {
+    fn default() -> Self {
+        BedrockSettings2D {
+            bottom: 0,
+            thickness: 3,
+            variation: 3,
+        }
+    }
+}
 
 pub struct TerrainGen2D {
     pub seed: u32,
+    pub surface: Option<SurfaceSettings2D>,
+    pub bedrock: Option<BedrockSettings2D>,
     noise: PerlinSurflet,
+    caves: Option<CaveMap2D>,
 }
 
 impl TerrainGen2D {
@@ -12,7 +68,61 @@ impl TerrainGen2D {
 
     pub fn new(seed: u32) -> TerrainGen2D {
         let noise = PerlinSurflet::new(seed);
-        TerrainGen2D { noise, seed }
+        TerrainGen2D {
+            noise,
+            seed,
+            surface: None,
+            bedrock: None,
+            caves: None,
+        }
+    }
+
+    /// Generate a connected cave system for the region. Generated chunks will use it instead of noise thresholding for open space.
+    ///
+    /// If a surface is set, the caves start from its highest point so that the main tunnel always breaks through it.
+    pub fn generate_caves(&mut self, settings: CaveSettings2D, region: Rect2I) {
+        let mut region = region;
+        if let Some(surface) = &self.surface {
+            region.max.y = region.max.y.min(surface.max_height());
+        }
+        self.caves = Some(CaveGen2D::new(self.seed, settings).generate(region));
+    }
+
+    /// Cave system made by `generate_caves`
+    pub fn caves(&self) -> Option<&CaveMap2D> {
+        self.caves.as_ref()
+    }
+
+    /// Height of the surface at the column, if the surface is enabled
+    pub fn surface_height(&self, x: i32) -> Option<i32> {
+        self.surface.as_ref().map(|surface| {
+            let value = self.noise.get([x as f64 * surface.frequency, 0.5])
+                + self.noise.get([x as f64 * surface.frequency * 4.0, 10.5]) * 0.25;
+            surface.height + (value.clamp(-1.0, 1.0) * surface.amplitude as f64) as i32
+        })
+    }
+
+    /// Highest row of the bedrock at the column, if the bedrock is enabled
+    pub fn bedrock_height(&self, x: i32) -> Option<i32> {
+        self.bedrock.as_ref().map(|bedrock| {
+            let value = (self.noise.get([x as f64 / 7.0, 20.5]) + 1.0) / 2.0;
+            bedrock.bottom
+                + bedrock.thickness
+                + (value.clamp(0.0, 1.0) * bedrock.variation as f64) as i32
+                - 1
+        })
+    }
+
+    /// Generate and add every chunk that overlaps the region
+    pub fn gen_region(&self, terrain: &mut Terrain2D, region: &Rect2I) {
+        let min = global_to_chunk_index(&region.min);
+        let max = global_to_chunk_index(&region.max);
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                let position = Vector2I { x, y };
+                terrain.add_chunk(position, self.gen_chunk(&position));
+            }
+        }
     }
 
     pub fn gen_chunk(&self, position: &Chunk2DIndex) -> Chunk2D {
@@ -39,6 +149,35 @@ impl TerrainGen2D {
                 id = 13;
             }
 
+            // Caves decide what is open, noise only picks the material
+            if let Some(caves) = &self.caves {
+                id = if caves.is_open(&global) {
+                    0
+                } else {
+                    id.max(11)
+                };
+            }
+
+            if let (Some(surface), Some(surface_height)) =
+                (&self.surface, self.surface_height(global.x))
+            {
+                if global.y > surface_height {
+                    id = 0;
+                } else if id != 0 && global.y > surface_height - surface.dirt_depth {
+                    id = if surface.grass && global.y == surface_height {
+                        15
+                    } else {
+                        14
+                    };
+                }
+            }
+
+            if let Some(bedrock_height) = self.bedrock_height(global.x) {
+                if global.y <= bedrock_height {
+                    id = 16;
+                }
+            }
+
             chunk.set_texel(&local, Texel2D { id, ..default() }, None);
         }
         chunk
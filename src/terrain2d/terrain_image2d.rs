@@ -0,0 +1,245 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use super::*;
+use crate::util::Rect2I;
+
+/// RGBA color with 8 bits per channel
+pub type Rgba8 = [u8; 4];
+
+pub fn color_to_rgba8(color: Color) -> Rgba8 {
+    color.as_rgba_u32().to_le_bytes()
+}
+
+/// Mapping from image colors to materials
+#[derive(Clone, Debug, Default)]
+pub struct TexelPalette2D {
+    colors: HashMap<Rgba8, TexelID>,
+}
+
+impl TexelPalette2D {
+    pub fn new() -> TexelPalette2D {
+        TexelPalette2D::default()
+    }
+
+    /// Palette that maps each material's `TexelBehaviour2D::color` to its ID.
+    ///
+    /// Some materials share a color (e.g. sand and loose sand). In that case the material without gravity wins,
+    /// since painted terrain is expected to stay in place.
+    pub fn from_behaviours() -> TexelPalette2D {
+        let mut palette = TexelPalette2D::new();
+        let mut behaviours = TexelBehaviour2D::all();
+        behaviours.sort_by_key(|(id, behaviour)| (behaviour.gravity.is_some(), *id));
+        for (id, behaviour) in behaviours.iter() {
+            palette
+                .colors
+                .entry(color_to_rgba8(behaviour.color))
+                .or_insert(*id);
+        }
+        palette
+    }
+
+    pub fn insert(&mut self, color: Rgba8, id: TexelID) {
+        self.colors.insert(color, id);
+    }
+
+    /// Fully transparent pixels are always empty
+    pub fn get(&self, color: &Rgba8) -> Option<TexelID> {
+        if color[3] == 0 {
+            return Some(Texel2D::EMPTY);
+        }
+        self.colors.get(color).copied()
+    }
+}
+
+#[derive(Debug)]
+pub enum TerrainImageError2D {
+    Io(io::Error),
+    Decoding(png::DecodingError),
+    Encoding(png::EncodingError),
+    /// Pixel color is missing from the palette. Position is in image coordinates, starting from the top-left corner.
+    UnknownColor {
+        color: Rgba8,
+        x: u32,
+        y: u32,
+    },
+}
+
+impl fmt::Display for TerrainImageError2D {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerrainImageError2D::Io(err) => write!(f, "io error: {err}"),
+            TerrainImageError2D::Decoding(err) => write!(f, "png decoding error: {err}"),
+            TerrainImageError2D::Encoding(err) => write!(f, "png encoding error: {err}"),
+            TerrainImageError2D::UnknownColor { color, x, y } => write!(
+                f,
+                "unknown color #{:02x}{:02x}{:02x}{:02x} at pixel ({x}, {y})",
+                color[0], color[1], color[2], color[3]
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TerrainImageError2D {}
+
+impl From<io::Error> for TerrainImageError2D {
+    fn from(err: io::Error) -> Self {
+        TerrainImageError2D::Io(err)
+    }
+}
+
+impl From<png::DecodingError> for TerrainImageError2D {
+    fn from(err: png::DecodingError) -> Self {
+        TerrainImageError2D::Decoding(err)
+    }
+}
+
+impl From<png::EncodingError> for TerrainImageError2D {
+    fn from(err: png::EncodingError) -> Self {
+        TerrainImageError2D::Encoding(err)
+    }
+}
+
+/// Decode a PNG into RGBA pixels, top row first. Returns the width, height and pixel data.
+pub fn decode_png_rgba8(reader: impl Read) -> Result<(u32, u32, Vec<u8>), TerrainImageError2D> {
+    let mut decoder = png::Decoder::new(reader);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+
+    let pixel_count = (info.width * info.height) as usize;
+    let mut data = Vec::with_capacity(pixel_count * 4);
+    for row in buffer[..info.line_size * info.height as usize].chunks(info.line_size) {
+        let row = &row[..info.width as usize * info.color_type.samples()];
+        match info.color_type {
+            png::ColorType::Rgba => data.extend_from_slice(row),
+            png::ColorType::Rgb => {
+                for rgb in row.chunks(3) {
+                    data.extend_from_slice(&[rgb[0], rgb[1], rgb[2], u8::MAX]);
+                }
+            }
+            png::ColorType::GrayscaleAlpha => {
+                for ga in row.chunks(2) {
+                    data.extend_from_slice(&[ga[0], ga[0], ga[0], ga[1]]);
+                }
+            }
+            png::ColorType::Grayscale | png::ColorType::Indexed => {
+                for g in row.iter() {
+                    data.extend_from_slice(&[*g, *g, *g, u8::MAX]);
+                }
+            }
+        }
+    }
+    Ok((info.width, info.height, data))
+}
+
+/// Encode RGBA pixels (top row first) as PNG
+pub fn encode_png_rgba8(
+    writer: impl Write,
+    width: u32,
+    height: u32,
+    data: &[u8],
+) -> Result<(), TerrainImageError2D> {
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(data)?;
+    writer.finish()?;
+    Ok(())
+}
+
+impl Terrain2D {
+    /// Import a PNG so that its bottom-left pixel lands on the given global position.
+    ///
+    /// Every pixel is validated before anything is written, so an unknown color leaves the terrain untouched.
+    pub fn import_png(
+        &mut self,
+        path: impl AsRef<Path>,
+        origin: &Vector2I,
+        palette: &TexelPalette2D,
+    ) -> Result<(), TerrainImageError2D> {
+        let file = File::open(path)?;
+        self.import_png_from(BufReader::new(file), origin, palette)
+    }
+
+    pub fn import_png_from(
+        &mut self,
+        reader: impl Read,
+        origin: &Vector2I,
+        palette: &TexelPalette2D,
+    ) -> Result<(), TerrainImageError2D> {
+        let (width, height, data) = decode_png_rgba8(reader)?;
+
+        let mut ids = Vec::with_capacity((width * height) as usize);
+        for (i, pixel) in data.chunks(4).enumerate() {
+            let color = [pixel[0], pixel[1], pixel[2], pixel[3]];
+            match palette.get(&color) {
+                Some(id) => ids.push(id),
+                None => {
+                    return Err(TerrainImageError2D::UnknownColor {
+                        color,
+                        x: i as u32 % width,
+                        y: i as u32 / width,
+                    })
+                }
+            }
+        }
+
+        let previous_cause = self.set_change_cause(TexelChangeCause2D::Import);
+        for (i, id) in ids.into_iter().enumerate() {
+            let global = *origin
+                + Vector2I {
+                    x: (i as u32 % width) as i32,
+                    y: (height - 1 - i as u32 / width) as i32,
+                };
+            self.set_texel(&global, Texel2D { id, ..default() }, None);
+        }
+        self.set_change_cause(previous_cause);
+        Ok(())
+    }
+
+    /// Colors of the texels in the rect as RGBA pixels, top row first. Empty texels are fully transparent.
+    pub fn rect_to_rgba8(&self, rect: &Rect2I) -> Vec<u8> {
+        let mut data = Vec::with_capacity(rect.area() * 4);
+        for y in (rect.min.y..=rect.max.y).rev() {
+            for x in rect.min.x..=rect.max.x {
+                let color = self
+                    .get_texel(&Vector2I { x, y })
+                    .and_then(|texel| texel.behaviour())
+                    .map_or([0, 0, 0, 0], |behaviour| color_to_rgba8(behaviour.color));
+                data.extend_from_slice(&color);
+            }
+        }
+        data
+    }
+
+    /// Export the rect as PNG using the colors of the materials
+    pub fn export_png(
+        &self,
+        path: impl AsRef<Path>,
+        rect: &Rect2I,
+    ) -> Result<(), TerrainImageError2D> {
+        let file = File::create(path)?;
+        self.export_png_to(BufWriter::new(file), rect)
+    }
+
+    pub fn export_png_to(
+        &self,
+        writer: impl Write,
+        rect: &Rect2I,
+    ) -> Result<(), TerrainImageError2D> {
+        encode_png_rgba8(
+            writer,
+            rect.width() as u32,
+            rect.height() as u32,
+            &self.rect_to_rgba8(rect),
+        )
+    }
+}
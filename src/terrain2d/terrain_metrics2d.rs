@@ -0,0 +1,149 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::Instant,
+};
+
+use super::*;
+
+/// Timings and counters of the terrain systems during a single frame. Timings are in milliseconds.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TerrainFrameMetrics {
+    pub frame: u64,
+    /// Texel simulation in `terrain_simulation`, excluding gas dispersion
+    pub simulation_ms: f32,
+    pub gas_dispersion_ms: f32,
+    pub sprite_sync_ms: f32,
+    /// Time spent in `chunk_collision_sync`. The outlines are built in background tasks, which are not included.
+    pub collision_sync_ms: f32,
+    /// Chunks that had a dirty rect to simulate
+    pub active_chunks: u32,
+    pub simulated_texels: u32,
+    /// Chunks whose colliders were replaced
+    pub collider_rebuilds: u32,
+}
+
+impl TerrainFrameMetrics {
+    pub const CSV_HEADER: &'static str = "frame,simulation_ms,gas_dispersion_ms,sprite_sync_ms,collision_sync_ms,active_chunks,simulated_texels,collider_rebuilds";
+
+    pub fn total_ms(&self) -> f32 {
+        self.simulation_ms + self.gas_dispersion_ms + self.sprite_sync_ms + self.collision_sync_ms
+    }
+
+    pub fn to_csv_row(&self) -> String {
+        format!(
+            "{},{:.4},{:.4},{:.4},{:.4},{},{},{}",
+            self.frame,
+            self.simulation_ms,
+            self.gas_dispersion_ms,
+            self.sprite_sync_ms,
+            self.collision_sync_ms,
+            self.active_chunks,
+            self.simulated_texels,
+            self.collider_rebuilds
+        )
+    }
+}
+
+/// Rolling history of `TerrainFrameMetrics`. The terrain systems write into the current frame, which is moved into
+/// the history at the end of the frame.
+#[derive(Resource)]
+pub struct TerrainMetrics {
+    pub current: TerrainFrameMetrics,
+    history: VecDeque<TerrainFrameMetrics>,
+    /// Number of frames kept in the history
+    pub max_frames: usize,
+}
+
+impl Default for TerrainMetrics {
+    fn default() -> Self {
+        TerrainMetrics {
+            current: TerrainFrameMetrics::default(),
+            history: VecDeque::new(),
+            max_frames: 600,
+        }
+    }
+}
+
+impl TerrainMetrics {
+    /// Completed frames, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &TerrainFrameMetrics> {
+        self.history.iter()
+    }
+
+    pub fn latest(&self) -> Option<&TerrainFrameMetrics> {
+        self.history.back()
+    }
+
+    /// Average of the completed frames, with the frame of the latest one
+    pub fn average(&self) -> TerrainFrameMetrics {
+        let len = self.history.len().max(1);
+        let mut sum = self
+            .history
+            .iter()
+            .fold(TerrainFrameMetrics::default(), |sum, frame| {
+                TerrainFrameMetrics {
+                    frame: frame.frame,
+                    simulation_ms: sum.simulation_ms + frame.simulation_ms,
+                    gas_dispersion_ms: sum.gas_dispersion_ms + frame.gas_dispersion_ms,
+                    sprite_sync_ms: sum.sprite_sync_ms + frame.sprite_sync_ms,
+                    collision_sync_ms: sum.collision_sync_ms + frame.collision_sync_ms,
+                    active_chunks: sum.active_chunks + frame.active_chunks,
+                    simulated_texels: sum.simulated_texels + frame.simulated_texels,
+                    collider_rebuilds: sum.collider_rebuilds + frame.collider_rebuilds,
+                }
+            });
+        sum.simulation_ms /= len as f32;
+        sum.gas_dispersion_ms /= len as f32;
+        sum.sprite_sync_ms /= len as f32;
+        sum.collision_sync_ms /= len as f32;
+        sum.active_chunks /= len as u32;
+        sum.simulated_texels /= len as u32;
+        sum.collider_rebuilds /= len as u32;
+        sum
+    }
+
+    /// Move the current frame into the history
+    pub fn finish_frame(&mut self, frame: u64) {
+        self.current.frame = frame;
+        self.history.push_back(self.current);
+        self.current = TerrainFrameMetrics::default();
+        while self.history.len() > self.max_frames {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.current = TerrainFrameMetrics::default();
+        self.history.clear();
+    }
+
+    /// Write the history as CSV, with a header row
+    pub fn write_csv(&self, writer: &mut impl Write) -> io::Result<()> {
+        writeln!(writer, "{}", TerrainFrameMetrics::CSV_HEADER)?;
+        for frame in self.history.iter() {
+            writeln!(writer, "{}", frame.to_csv_row())?;
+        }
+        Ok(())
+    }
+
+    pub fn export_csv(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_csv(&mut writer)?;
+        writer.flush()
+    }
+}
+
+/// Milliseconds since the instant
+pub(crate) fn elapsed_ms(start: Instant) -> f32 {
+    start.elapsed().as_secs_f32() * 1000.0
+}
+
+pub fn terrain_metrics_frame(
+    frame_counter: Res<FrameCounter>,
+    mut metrics: ResMut<TerrainMetrics>,
+) {
+    metrics.finish_frame(frame_counter.frame);
+}
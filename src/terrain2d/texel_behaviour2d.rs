@@ -1,7 +1,8 @@
-use crate::util::Vector2I;
+use crate::util::{CollisionLayers, Vector2I};
 
 use super::TexelID;
 use bevy::prelude::*;
+use bevy_rapier2d::prelude::Group;
 use lazy_static::lazy_static;
 use std::{borrow::Cow, collections::HashMap};
 
@@ -126,6 +127,97 @@ lazy_static! {
             },
         );
 
+        result.insert(
+            14,
+            TexelBehaviour2D {
+                name: Cow::Borrowed("dirt"),
+                color: Color::rgb(0.36, 0.25, 0.16),
+                has_collision: true,
+                ..default()
+            },
+        );
+
+        result.insert(
+            15,
+            TexelBehaviour2D {
+                name: Cow::Borrowed("grass"),
+                color: Color::rgb(0.29, 0.55, 0.2),
+                has_collision: true,
+                spread: Some(TexelSpread2D {
+                    target: 14,
+                    chance: 0.02,
+                }),
+                ..default()
+            },
+        );
+
+        result.insert(
+            16,
+            TexelBehaviour2D {
+                name: Cow::Borrowed("bedrock"),
+                color: Color::rgb(0.05, 0.04, 0.06),
+                has_collision: true,
+                indestructible: true,
+                ..default()
+            },
+        );
+
+        result.insert(
+            17,
+            TexelBehaviour2D {
+                name: Cow::Borrowed("ice"),
+                color: Color::rgb(0.67, 0.85, 0.95),
+                has_collision: true,
+                friction: 0.02,
+                ..default()
+            },
+        );
+
+        result.insert(
+            18,
+            TexelBehaviour2D {
+                name: Cow::Borrowed("rubber"),
+                color: Color::rgb(0.55, 0.12, 0.2),
+                has_collision: true,
+                restitution: 0.8,
+                ..default()
+            },
+        );
+
+        result.insert(
+            19,
+            TexelBehaviour2D {
+                name: Cow::Borrowed("wooden platform"),
+                color: Color::rgb(0.52, 0.37, 0.2),
+                has_collision: true,
+                one_way: true,
+                ..default()
+            },
+        );
+
+        result.insert(
+            20,
+            TexelBehaviour2D {
+                name: Cow::Borrowed("enemy barrier"),
+                color: Color::rgb(0.7, 0.25, 0.3),
+                has_collision: true,
+                indestructible: true,
+                collision_filter: CollisionLayers::ENEMY,
+                ..default()
+            },
+        );
+
+        result.insert(
+            21,
+            TexelBehaviour2D {
+                name: Cow::Borrowed("mesh"),
+                color: Color::rgb(0.45, 0.47, 0.5),
+                has_collision: true,
+                collision_filter: Group::ALL.difference(CollisionLayers::PROJECTILE),
+                ..default()
+            },
+        );
+
         result
     };
 }
@@ -156,6 +248,25 @@ impl From<TexelGravity> for Vector2I {
     }
 }
 
+/// Material that grows onto neighbouring texels of the target material when they are exposed, e.g. grass on dirt.
+/// When the material itself is no longer exposed, it turns back into the target material.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TexelSpread2D {
+    pub target: TexelID,
+    /// Chance per simulation step to spread to a single neighbour
+    pub chance: f32,
+}
+
+/// Physical properties of the terrain colliders. Islands are split so that each collider has a single set of
+/// properties.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TexelColliderProperties2D {
+    pub friction: f32,
+    pub restitution: f32,
+    pub one_way: bool,
+    pub collision_filter: Group,
+}
+
 #[derive(Clone, Debug)]
 pub struct TexelBehaviour2D {
     pub name: Cow<'static, str>,
@@ -164,6 +275,15 @@ pub struct TexelBehaviour2D {
     pub has_collision: bool,
     pub gravity: Option<TexelGravity>,
     pub toughness: Option<f32>,
+    /// Indestructible texels can't be replaced through `Terrain2D::set_texel`
+    pub indestructible: bool,
+    pub spread: Option<TexelSpread2D>,
+    pub friction: f32,
+    pub restitution: f32,
+    /// One-way colliders only block movement from above, like platforms that can be jumped through from below
+    pub one_way: bool,
+    /// Collision groups the material blocks, e.g. a barrier that only stops enemies
+    pub collision_filter: Group,
 }
 
 impl Default for TexelBehaviour2D {
@@ -175,6 +295,12 @@ impl Default for TexelBehaviour2D {
             has_collision: false,
             gravity: None,
             toughness: None,
+            indestructible: false,
+            spread: None,
+            friction: 0.5,
+            restitution: 0.0,
+            one_way: false,
+            collision_filter: Group::ALL,
         }
     }
 }
@@ -187,12 +313,25 @@ impl TexelBehaviour2D {
         form: TexelForm::Solid,
         gravity: None,
         toughness: None,
+        indestructible: true,
+        spread: None,
+        friction: 0.5,
+        restitution: 0.0,
+        one_way: false,
+        collision_filter: Group::ALL,
     };
 
     pub fn from_id(id: &TexelID) -> Option<Self> {
         ID_MAP.get(id).cloned()
     }
 
+    /// All defined materials, sorted by ID
+    pub fn all() -> Vec<(TexelID, TexelBehaviour2D)> {
+        let mut result: Vec<_> = ID_MAP.iter().map(|(id, b)| (*id, b.clone())).collect();
+        result.sort_unstable_by_key(|(id, _)| *id);
+        result
+    }
+
     pub fn is_empty(id: &TexelID) -> bool {
         ID_MAP.get(id).is_none()
     }
@@ -201,6 +340,38 @@ impl TexelBehaviour2D {
         ID_MAP.get(id).map_or(false, |b| b.has_collision)
     }
 
+    /// Collider properties of the material, None if it has no collision
+    pub fn collider_properties(id: &TexelID) -> Option<TexelColliderProperties2D> {
+        ID_MAP
+            .get(id)
+            .filter(|b| b.has_collision)
+            .map(|b| b.collider())
+    }
+
+    pub fn collider(&self) -> TexelColliderProperties2D {
+        TexelColliderProperties2D {
+            friction: self.friction,
+            restitution: self.restitution,
+            one_way: self.one_way,
+            collision_filter: self.collision_filter,
+        }
+    }
+
+    pub fn has_gravity(id: &TexelID) -> bool {
+        ID_MAP.get(id).map_or(false, |b| b.gravity.is_some())
+    }
+
+    pub fn is_indestructible(id: &TexelID) -> bool {
+        ID_MAP.get(id).map_or(false, |b| b.indestructible)
+    }
+
+    /// Is the texel open to air, i.e. empty or gas
+    pub fn is_open(behaviour: &Option<TexelBehaviour2D>) -> bool {
+        behaviour
+            .as_ref()
+            .map_or(true, |b| b.form == TexelForm::Gas)
+    }
+
     /// Can this type of material displace another?
     pub fn can_displace(from: &TexelBehaviour2D, to: &Option<TexelBehaviour2D>) -> bool {
         let to = if let Some(to) = to { to } else { return true };
@@ -3,11 +3,17 @@ use bevy::prelude::*;
 mod collision_layers;
 pub mod frame_counter;
 pub mod math;
+pub mod polyline;
+mod random;
+pub mod raster;
+mod rect2_i32;
 mod segment2_i32;
 mod vector2;
 mod vector2_i32;
 
 pub use collision_layers::*;
+pub use random::*;
+pub use rect2_i32::*;
 pub use segment2_i32::*;
 pub use vector2::*;
 pub use vector2_i32::*;
@@ -1,3 +1,4 @@
+use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
 
 pub struct CollisionLayers;
@@ -6,4 +7,11 @@ impl CollisionLayers {
     pub const WORLD: Group = Group::GROUP_1;
     pub const PLAYER: Group = Group::GROUP_2;
     pub const ENEMY: Group = Group::GROUP_3;
+    pub const PROJECTILE: Group = Group::GROUP_4;
 }
+
+/// Collider that only blocks movement from above, like a platform that can be jumped through from below.
+/// Respected by kinematic movement.
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct OneWayCollider;
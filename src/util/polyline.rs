@@ -0,0 +1,575 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+};
+
+use bevy::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Simplification {
+    /// Keeps the points that deviate the most. Fast and preserves sharp corners.
+    DouglasPeucker,
+    /// Removes the points that form the smallest triangles first. Produces smoother outlines.
+    Visvalingam,
+}
+
+/// Simplify polylines. Closed polylines have the same first and last point.
+///
+/// The result only contains points of the original polylines, and no original point is further than
+/// `tolerance` from the simplified outline. Closed polylines keep at least three distinct points and open polylines
+/// keep their endpoints, so that they still connect to whatever continues them.
+/// Simplified segments never cross or touch each other, also between different polylines, unless the original
+/// polylines touched at the same point.
+pub fn simplify_polylines(
+    polylines: &[Vec<Vec2>],
+    method: Simplification,
+    tolerance: f32,
+) -> Vec<Vec<Vec2>> {
+    let outlines: Vec<Outline> = polylines
+        .iter()
+        .map(|polyline| match polyline.split_last() {
+            Some((last, rest)) if Some(last) == rest.first() => Outline {
+                points: rest,
+                closed: true,
+            },
+            _ => Outline {
+                points: &polyline[..],
+                closed: false,
+            },
+        })
+        .collect();
+
+    let mut keeps: Vec<Vec<bool>> = outlines
+        .iter()
+        .map(|outline| match method {
+            Simplification::DouglasPeucker => douglas_peucker(outline, tolerance),
+            Simplification::Visvalingam => visvalingam(outline, tolerance),
+        })
+        .collect();
+
+    remove_intersections(&outlines, &mut keeps, tolerance);
+
+    outlines
+        .iter()
+        .zip(keeps.iter())
+        .map(|(outline, keep)| {
+            let mut points: Vec<Vec2> = outline
+                .points
+                .iter()
+                .zip(keep.iter())
+                .filter(|(_, keep)| **keep)
+                .map(|(point, _)| *point)
+                .collect();
+            if outline.closed {
+                if let Some(first) = points.first() {
+                    points.push(*first);
+                }
+            }
+            points
+        })
+        .collect()
+}
+
+/// Points of a polyline. Closed polylines don't repeat the first point at the end.
+struct Outline<'a> {
+    points: &'a [Vec2],
+    closed: bool,
+}
+
+impl Outline<'_> {
+    /// Simplified segments as pairs of point indices
+    fn segments(&self, keep: &[bool]) -> Vec<(usize, usize)> {
+        let kept: Vec<usize> = (0..keep.len()).filter(|i| keep[*i]).collect();
+        let count = if self.closed {
+            kept.len()
+        } else {
+            kept.len().saturating_sub(1)
+        };
+        (0..count)
+            .map(|k| (kept[k], kept[(k + 1) % kept.len()]))
+            .collect()
+    }
+}
+
+fn distance_to_segment(point: Vec2, from: Vec2, to: Vec2) -> f32 {
+    let segment = to - from;
+    let length_squared = segment.length_squared();
+    if length_squared <= f32::EPSILON {
+        return point.distance(from);
+    }
+    let t = ((point - from).dot(segment) / length_squared).clamp(0.0, 1.0);
+    point.distance(from + segment * t)
+}
+
+/// Index of the point with the largest distance from the segment between `from` and `to`, and the distance.
+/// Indices wrap around, which is only needed for closed outlines.
+fn farthest_between(ring: &[Vec2], from: usize, to: usize) -> Option<(usize, f32)> {
+    let len = ring.len();
+    let span = (to + len - from) % len;
+    let span = if span == 0 { len } else { span };
+    (1..span)
+        .map(|offset| {
+            let i = (from + offset) % len;
+            (i, distance_to_segment(ring[i], ring[from], ring[to % len]))
+        })
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+}
+
+/// Keep points between `from` and `to` until every point of the span is within tolerance of the kept ones.
+///
+/// Needed whenever a point is kept in the middle of a simplified segment: the points around it were within
+/// tolerance of the segment, but not necessarily of the two shorter segments that replace it.
+fn refine_span(ring: &[Vec2], from: usize, to: usize, tolerance: f32, keep: &mut [bool]) {
+    let mut stack = vec![(from, to)];
+    while let Some((from, to)) = stack.pop() {
+        if let Some((i, distance)) = farthest_between(ring, from, to) {
+            if distance > tolerance {
+                keep[i] = true;
+                stack.push((from, i));
+                stack.push((i, to));
+            }
+        }
+    }
+}
+
+/// Keep the farthest point of the segment and refine both halves. Returns the kept points in order, including the
+/// endpoints, or None if there are no points between the endpoints.
+fn split_span(
+    ring: &[Vec2],
+    from: usize,
+    to: usize,
+    tolerance: f32,
+    keep: &mut [bool],
+) -> Option<Vec<usize>> {
+    let (i, _) = farthest_between(ring, from, to)?;
+    keep[i] = true;
+    refine_span(ring, from, i, tolerance, keep);
+    refine_span(ring, i, to, tolerance, keep);
+
+    let len = ring.len();
+    let span = (to + len - from) % len;
+    let span = if span == 0 { len } else { span };
+    let mut kept = vec![from];
+    kept.extend(
+        (1..span)
+            .map(|offset| (from + offset) % len)
+            .filter(|i| keep[*i]),
+    );
+    kept.push(to % len);
+    Some(kept)
+}
+
+fn ensure_minimum(ring: &[Vec2], keep: &mut [bool], tolerance: f32) {
+    while keep.iter().filter(|k| **k).count() < 3.min(ring.len()) {
+        let kept: Vec<usize> = (0..ring.len()).filter(|i| keep[*i]).collect();
+        let best = (0..kept.len())
+            .filter_map(|k| {
+                let (from, to) = (kept[k], kept[(k + 1) % kept.len()]);
+                farthest_between(ring, from, to).map(|(_, distance)| (from, to, distance))
+            })
+            .max_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(Ordering::Equal));
+        match best {
+            Some((from, to, _)) => {
+                split_span(ring, from, to, tolerance, keep);
+            }
+            None => break,
+        }
+    }
+}
+
+fn douglas_peucker(outline: &Outline, tolerance: f32) -> Vec<bool> {
+    let ring = outline.points;
+    let len = ring.len();
+    let mut keep = vec![false; len];
+    if len <= 3 {
+        return vec![true; len];
+    }
+
+    let spans = if outline.closed {
+        // Closed rings need two anchors, the first point and the point farthest from it
+        let anchor = (1..len)
+            .max_by(|a, b| {
+                ring[0]
+                    .distance_squared(ring[*a])
+                    .partial_cmp(&ring[0].distance_squared(ring[*b]))
+                    .unwrap_or(Ordering::Equal)
+            })
+            .unwrap();
+        keep[0] = true;
+        keep[anchor] = true;
+        vec![(0, anchor), (anchor, len)]
+    } else {
+        keep[0] = true;
+        keep[len - 1] = true;
+        vec![(0, len - 1)]
+    };
+    for (from, to) in spans {
+        refine_span(ring, from, to, tolerance, &mut keep);
+    }
+
+    if outline.closed {
+        ensure_minimum(ring, &mut keep, tolerance);
+    }
+    keep
+}
+
+#[derive(PartialEq)]
+struct Candidate {
+    area: f32,
+    index: usize,
+    version: u32,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed for a min-heap
+        other
+            .area
+            .partial_cmp(&self.area)
+            .unwrap_or(Ordering::Equal)
+            .then(other.index.cmp(&self.index))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn visvalingam(outline: &Outline, tolerance: f32) -> Vec<bool> {
+    let ring = outline.points;
+    let len = ring.len();
+    let mut keep = vec![true; len];
+    if len <= 3 {
+        return keep;
+    }
+    // Endpoints of open outlines are never removed
+    let is_candidate = |i: usize| outline.closed || (i != 0 && i != len - 1);
+    let minimum = if outline.closed { 3 } else { 2 };
+
+    let mut prev: Vec<usize> = (0..len).map(|i| (i + len - 1) % len).collect();
+    let mut next: Vec<usize> = (0..len).map(|i| (i + 1) % len).collect();
+    let mut versions = vec![0u32; len];
+    let area = |prev: usize, i: usize, next: usize| {
+        (ring[i] - ring[prev])
+            .perp_dot(ring[next] - ring[prev])
+            .abs()
+            / 2.0
+    };
+
+    let mut heap: BinaryHeap<Candidate> = (0..len)
+        .filter(|i| is_candidate(*i))
+        .map(|i| Candidate {
+            area: area(prev[i], i, next[i]),
+            index: i,
+            version: 0,
+        })
+        .collect();
+
+    let mut remaining = len;
+    while let Some(candidate) = heap.pop() {
+        if remaining <= minimum {
+            break;
+        }
+        let i = candidate.index;
+        if !keep[i] || versions[i] != candidate.version {
+            continue;
+        }
+        // Removing the point must keep every original point of the span within tolerance.
+        // Blocked points are checked again when one of their neighbours is removed.
+        let within_tolerance = farthest_between(ring, prev[i], next[i])
+            .map_or(true, |(_, distance)| distance <= tolerance);
+        if !within_tolerance {
+            continue;
+        }
+
+        keep[i] = false;
+        remaining -= 1;
+        let (p, n) = (prev[i], next[i]);
+        next[p] = n;
+        prev[n] = p;
+        for neighbour in [p, n].into_iter().filter(|i| is_candidate(*i)) {
+            versions[neighbour] += 1;
+            heap.push(Candidate {
+                area: area(prev[neighbour], neighbour, next[neighbour]),
+                index: neighbour,
+                version: versions[neighbour],
+            });
+        }
+    }
+
+    keep
+}
+
+/// Positive when `c` is to the left of the line from `a` to `b`
+fn orientation(a: Vec2, b: Vec2, c: Vec2) -> f32 {
+    (b - a).perp_dot(c - a)
+}
+
+fn on_segment(point: Vec2, from: Vec2, to: Vec2) -> bool {
+    orientation(from, to, point) == 0.0
+        && point.x >= from.x.min(to.x)
+        && point.x <= from.x.max(to.x)
+        && point.y >= from.y.min(to.y)
+        && point.y <= from.y.max(to.y)
+}
+
+/// Do the segments share any point other than a common endpoint
+fn segments_conflict(a: (Vec2, Vec2), b: (Vec2, Vec2)) -> bool {
+    if a.0.max(a.1).cmplt(b.0.min(b.1)).any() || b.0.max(b.1).cmplt(a.0.min(a.1)).any() {
+        return false;
+    }
+
+    let o1 = orientation(a.0, a.1, b.0);
+    let o2 = orientation(a.0, a.1, b.1);
+    let o3 = orientation(b.0, b.1, a.0);
+    let o4 = orientation(b.0, b.1, a.1);
+    if o1 * o2 < 0.0 && o3 * o4 < 0.0 {
+        return true;
+    }
+
+    let is_shared = |point: Vec2| (point == a.0 || point == a.1) && (point == b.0 || point == b.1);
+    [b.0, b.1]
+        .iter()
+        .any(|point| !is_shared(*point) && on_segment(*point, a.0, a.1))
+        || [a.0, a.1]
+            .iter()
+            .any(|point| !is_shared(*point) && on_segment(*point, b.0, b.1))
+}
+
+/// Simplified segment as the indices of its points in an outline
+#[derive(Clone, Copy)]
+struct SimplifiedSegment {
+    outline: usize,
+    from: usize,
+    to: usize,
+}
+
+/// Size of the cells of `SegmentGrid` in texels
+const GRID_CELL_SIZE: f32 = 4.0;
+
+/// Segments bucketed by the grid cells that their bounding boxes overlap
+#[derive(Default)]
+struct SegmentGrid {
+    cells: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl SegmentGrid {
+    fn cell_range(segment: (Vec2, Vec2)) -> (IVec2, IVec2) {
+        let min = (segment.0.min(segment.1) / GRID_CELL_SIZE)
+            .floor()
+            .as_ivec2();
+        let max = (segment.0.max(segment.1) / GRID_CELL_SIZE)
+            .floor()
+            .as_ivec2();
+        (min, max)
+    }
+
+    fn insert(&mut self, id: usize, segment: (Vec2, Vec2)) {
+        let (min, max) = Self::cell_range(segment);
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                self.cells.entry((x, y)).or_default().push(id);
+            }
+        }
+    }
+
+    /// Segments whose cells overlap the cells of the segment, sorted and without duplicates
+    fn candidates(&self, segment: (Vec2, Vec2)) -> Vec<usize> {
+        let (min, max) = Self::cell_range(segment);
+        let mut candidates = vec![];
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                if let Some(ids) = self.cells.get(&(x, y)) {
+                    candidates.extend_from_slice(ids);
+                }
+            }
+        }
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+}
+
+/// Restore original points until no simplified segments conflict with each other.
+///
+/// Only segments in the same grid cells are compared. A conflicting segment is split at its farthest original
+/// point, and only the new segments are checked again.
+fn remove_intersections(outlines: &[Outline], keeps: &mut [Vec<bool>], tolerance: f32) {
+    let points = |segment: SimplifiedSegment| {
+        let outline = outlines[segment.outline].points;
+        (outline[segment.from], outline[segment.to])
+    };
+
+    let mut segments: Vec<SimplifiedSegment> = vec![];
+    for (o, (outline, keep)) in outlines.iter().zip(keeps.iter()).enumerate() {
+        segments.extend(
+            outline
+                .segments(keep)
+                .into_iter()
+                .map(|(from, to)| SimplifiedSegment {
+                    outline: o,
+                    from,
+                    to,
+                }),
+        );
+    }
+    let mut grid = SegmentGrid::default();
+    for (id, segment) in segments.iter().enumerate() {
+        grid.insert(id, points(*segment));
+    }
+    let mut alive = vec![true; segments.len()];
+    let mut unchecked: Vec<usize> = (0..segments.len()).rev().collect();
+
+    while let Some(id) = unchecked.pop() {
+        if !alive[id] {
+            continue;
+        }
+        let a = points(segments[id]);
+        let conflicts: Vec<usize> = grid
+            .candidates(a)
+            .into_iter()
+            .filter(|other| {
+                *other != id && alive[*other] && segments_conflict(a, points(segments[*other]))
+            })
+            .collect();
+        if conflicts.is_empty() {
+            continue;
+        }
+
+        // Segments between neighbouring original points can't be split. Their conflicts were in the original.
+        for conflict in conflicts.into_iter().chain([id]) {
+            let segment = segments[conflict];
+            let kept = match split_span(
+                outlines[segment.outline].points,
+                segment.from,
+                segment.to,
+                tolerance,
+                &mut keeps[segment.outline],
+            ) {
+                Some(kept) => kept,
+                None => continue,
+            };
+            alive[conflict] = false;
+            for pair in kept.windows(2) {
+                let split = SimplifiedSegment {
+                    outline: segment.outline,
+                    from: pair[0],
+                    to: pair[1],
+                };
+                let split_id = segments.len();
+                segments.push(split);
+                alive.push(true);
+                grid.insert(split_id, points(split));
+                unchecked.push(split_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{terrain2d::*, util::Random};
+
+    const TOLERANCE: f32 = 1.0;
+    /// Stone, ice and rubber, so that outlines of different materials touch each other
+    const MATERIALS: [TexelID; 3] = [12, 17, 18];
+
+    /// Collider outlines of a random chunk. Without neighbouring chunks, the outline of a material is only open where
+    /// it touches another material.
+    fn random_outlines(random: &mut Random, density: f64) -> Vec<ColliderOutline2D> {
+        let mut chunk = Chunk2D::new();
+        for local in Chunk2D::xy_vec() {
+            if random.chance(density) {
+                let id = MATERIALS[random.range_i32(0, MATERIALS.len() as i32) as usize];
+                chunk.set_texel(&local, Texel2D { id, ..default() }, None);
+            }
+        }
+        chunk.create_collision_data(&ChunkBorder2D::default(), &ChunkMotion2D::NONE)
+    }
+
+    fn segment_key(from: Vec2, to: Vec2) -> (IVec2, IVec2) {
+        (from.as_ivec2(), to.as_ivec2())
+    }
+
+    fn check_invariants(method: Simplification, seed: u64) {
+        let mut random = Random::new(seed);
+        for round in 0..100 {
+            let density = [0.2, 0.4, 0.5, 0.6, 0.8][round % 5];
+            let outlines = random_outlines(&mut random, density);
+            let settings = ChunkColliderSettings2D {
+                simplification: Some(method),
+                tolerance: TOLERANCE,
+                ..default()
+            };
+            let simplified = settings.simplify(outlines.clone());
+            assert_eq!(simplified.len(), outlines.len());
+
+            for (original, outline) in outlines.iter().zip(simplified.iter()) {
+                // Outlines of a material that touches another material are open, and keep their endpoints
+                let points = &outline.polyline;
+                assert_eq!(points.first(), original.polyline.first(), "round {round}");
+                assert_eq!(points.last(), original.polyline.last(), "round {round}");
+                if original.polyline.first() == original.polyline.last() {
+                    assert!(points.len() >= 4, "round {round}: degenerate outline");
+                }
+
+                // Every point of the simplified outline stays within tolerance of the original outline, so it
+                // can't cut into the solid texels by more than that
+                for pair in points.windows(2) {
+                    for step in 0..=8 {
+                        let point = pair[0].lerp(pair[1], step as f32 / 8.0);
+                        let distance = original
+                            .polyline
+                            .windows(2)
+                            .map(|segment| distance_to_segment(point, segment[0], segment[1]))
+                            .fold(f32::MAX, f32::min);
+                        assert!(
+                            distance <= TOLERANCE + 1e-4,
+                            "round {round}: {point} is {distance} from the original outline"
+                        );
+                    }
+                }
+            }
+
+            // Simplified segments only conflict where the original segments already did
+            let original_segments: HashSet<(IVec2, IVec2)> = outlines
+                .iter()
+                .flat_map(|outline| outline.polyline.windows(2))
+                .map(|pair| segment_key(pair[0], pair[1]))
+                .collect();
+            let segments: Vec<(Vec2, Vec2)> = simplified
+                .iter()
+                .flat_map(|outline| outline.polyline.windows(2))
+                .map(|pair| (pair[0], pair[1]))
+                .collect();
+            for (i, a) in segments.iter().enumerate() {
+                for b in segments.iter().skip(i + 1) {
+                    if segments_conflict(*a, *b) {
+                        assert!(
+                            original_segments.contains(&segment_key(a.0, a.1))
+                                && original_segments.contains(&segment_key(b.0, b.1)),
+                            "round {round}: {a:?} intersects {b:?}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn douglas_peucker_invariants() {
+        check_invariants(Simplification::DouglasPeucker, 32);
+    }
+
+    #[test]
+    fn visvalingam_invariants() {
+        check_invariants(Simplification::Visvalingam, 33);
+    }
+}
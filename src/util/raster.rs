@@ -0,0 +1,112 @@
+//! Rasterization of shapes into texel positions.
+//!
+//! Float coordinates use the same space as the terrain, where the texel `(x, y)` covers the square from `(x, y)` to
+//! `(x + 1, y + 1)`. A texel belongs to a filled shape when its center is inside it.
+
+use bevy::prelude::*;
+
+use super::{Rect2I, Vector2I};
+
+/// Bresenham line, including both endpoints. Consecutive points touch at least diagonally.
+pub fn line(from: Vector2I, to: Vector2I) -> Vec<Vector2I> {
+    let dx = (to.x - from.x).abs();
+    let dy = -(to.y - from.y).abs();
+    let step = Vector2I::new((to.x - from.x).signum(), (to.y - from.y).signum());
+    let mut error = dx + dy;
+    let mut point = from;
+    let mut result = vec![point];
+    while point != to {
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            point.x += step.x;
+        }
+        if doubled <= dx {
+            error += dx;
+            point.y += step.y;
+        }
+        result.push(point);
+    }
+    result
+}
+
+/// Line with round caps, containing the texels within `width / 2` of the segment between the texels.
+/// Widths of one texel or less fall back to `line`.
+pub fn thick_line(from: Vector2I, to: Vector2I, width: f32) -> Vec<Vector2I> {
+    if width <= 1.0 {
+        return line(from, to);
+    }
+    let radius = width / 2.0;
+    let margin = Vector2I::ONE * radius.ceil() as i32;
+    let (a, b) = (Vec2::from(from), Vec2::from(to));
+    let segment = b - a;
+    let bounds = Rect2I::from_corners(from, to);
+    Rect2I::new(bounds.min - margin, bounds.max + margin)
+        .points()
+        .filter(|point| {
+            let point = Vec2::from(*point);
+            let t = if segment == Vec2::ZERO {
+                0.0
+            } else {
+                ((point - a).dot(segment) / segment.length_squared()).clamp(0.0, 1.0)
+            };
+            point.distance_squared(a + segment * t) <= radius * radius
+        })
+        .collect()
+}
+
+/// Texels whose distance from the center texel is at most `radius`
+pub fn filled_circle(center: Vector2I, radius: f32) -> Vec<Vector2I> {
+    if radius < 0.0 {
+        return vec![];
+    }
+    let margin = Vector2I::ONE * radius.floor() as i32;
+    Rect2I::new(center - margin, center + margin)
+        .points()
+        .filter(|point| {
+            let offset = *point - center;
+            ((offset.x * offset.x + offset.y * offset.y) as f32) <= radius * radius
+        })
+        .collect()
+}
+
+/// Texels inside the polygon by `polygon_contains`. The points don't repeat the first point at the end.
+pub fn filled_polygon(points: &[Vec2]) -> Vec<Vector2I> {
+    polygon_bounds(points)
+        .into_iter()
+        .flat_map(|bounds| bounds.points())
+        .filter(|point| polygon_contains(points, point))
+        .collect()
+}
+
+/// Smallest rectangle of the texels whose centers can be within the polygon
+pub fn polygon_bounds(points: &[Vec2]) -> Option<Rect2I> {
+    let first = *points.first()?;
+    let (min, max) = points.iter().fold((first, first), |(min, max), point| {
+        (min.min(*point), max.max(*point))
+    });
+    Some(Rect2I::new(
+        Vector2I::new((min.x - 0.5).ceil() as i32, (min.y - 0.5).ceil() as i32),
+        Vector2I::new((max.x - 0.5).floor() as i32, (max.y - 0.5).floor() as i32),
+    ))
+}
+
+/// Is the center of the texel inside the polygon by the even-odd rule. Shared by edits and region queries, so both
+/// agree on the texels along the edges.
+pub fn polygon_contains(points: &[Vec2], texel: &Vector2I) -> bool {
+    let center = Vec2::new(texel.x as f32 + 0.5, texel.y as f32 + 0.5);
+    let mut inside = false;
+    for (i, a) in points.iter().enumerate() {
+        let b = points[(i + 1) % points.len()];
+        if (a.y > center.y) != (b.y > center.y)
+            && center.x < a.x + (center.y - a.y) / (b.y - a.y) * (b.x - a.x)
+        {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+pub fn filled_rect(rect: &Rect2I) -> Vec<Vector2I> {
+    rect.points().collect()
+}
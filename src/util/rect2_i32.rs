@@ -0,0 +1,92 @@
+use super::Vector2I;
+use std::fmt;
+
+/// Integer rectangle. Both `min` and `max` are inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rect2I {
+    pub min: Vector2I,
+    pub max: Vector2I,
+}
+
+impl Rect2I {
+    pub fn new(min: Vector2I, max: Vector2I) -> Rect2I {
+        Rect2I { min, max }
+    }
+
+    /// Create the smallest rectangle that contains both points
+    pub fn from_corners(a: Vector2I, b: Vector2I) -> Rect2I {
+        Rect2I {
+            min: a.min(&b),
+            max: a.max(&b),
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.max.x - self.min.x + 1
+    }
+
+    pub fn height(&self) -> i32 {
+        self.max.y - self.min.y + 1
+    }
+
+    pub fn size(&self) -> Vector2I {
+        Vector2I::new(self.width(), self.height())
+    }
+
+    pub fn area(&self) -> usize {
+        (self.width().max(0) as usize) * (self.height().max(0) as usize)
+    }
+
+    pub fn contains(&self, point: &Vector2I) -> bool {
+        point.x >= self.min.x
+            && point.y >= self.min.y
+            && point.x <= self.max.x
+            && point.y <= self.max.y
+    }
+
+    pub fn include_point(&self, point: Vector2I) -> Rect2I {
+        Rect2I {
+            min: self.min.min(&point),
+            max: self.max.max(&point),
+        }
+    }
+
+    pub fn intersection(&self, other: &Rect2I) -> Option<Rect2I> {
+        let min = self.min.max(&other.min);
+        let max = self.max.min(&other.max);
+        if min.x > max.x || min.y > max.y {
+            None
+        } else {
+            Some(Rect2I { min, max })
+        }
+    }
+
+    /// Index of the point in a row-major buffer covering this rectangle
+    pub fn index_of(&self, point: &Vector2I) -> Option<usize> {
+        if !self.contains(point) {
+            return None;
+        }
+        Some(((point.y - self.min.y) * self.width() + (point.x - self.min.x)) as usize)
+    }
+
+    /// Inverse of `index_of`
+    pub fn point_at(&self, index: usize) -> Vector2I {
+        Vector2I {
+            x: self.min.x + index as i32 % self.width(),
+            y: self.min.y + index as i32 / self.width(),
+        }
+    }
+
+    /// All points of the rectangle, row by row starting from `min`
+    pub fn points(&self) -> impl Iterator<Item = Vector2I> {
+        let rect = *self;
+        (rect.min.y..=rect.max.y)
+            .flat_map(move |y| (rect.min.x..=rect.max.x).map(move |x| Vector2I { x, y }))
+    }
+}
+
+impl fmt::Display for Rect2I {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{} - {}]", self.min, self.max)
+    }
+}